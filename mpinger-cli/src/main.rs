@@ -1,12 +1,24 @@
 use anyhow::Result;
 use clap::Parser;
 use log::error;
-use mpinger::{MPinger, MPingerConfig, MPingerType, RunningAverage};
-use std::process;
+use metrics::Metrics;
+use mpinger::{
+    MPinger, MPingerConfig, MPingerConfigFile, MPingerDNSRecordType, MPingerDestinationConfig,
+    MPingerDestinationStats, MPingerDetails, MPingerHttpBodyMatch, MPingerHttpDetails,
+    MPingerHttpRequest, MPingerIpFamily, MPingerMessageKind, MPingerProbeType,
+    MPingerTlsChainStatus, MPingerTlsDetails, MPingerTlsOptions, MPingerType, MPingerUdpDetails,
+    RunningAverage,
+};
+use output::{to_ms, Output, OutputFormat, PingRecord, SummaryRecord};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::format_description::well_known::Rfc3339;
 use time::{OffsetDateTime, format_description};
 use tprint::{TPrint, TPrintAlign};
 
+mod metrics;
+mod output;
+
 #[derive(Parser)]
 struct Args {
     #[arg(short, long)]
@@ -17,6 +29,21 @@ struct Args {
     /// Interval between pings in ms
     #[arg(short, long, default_value = "1000")]
     interval: u64,
+    /// Send failed pings again up to RETRIES times before reporting them
+    #[arg(long, default_value = "0")]
+    retries: usize,
+    /// Resolve hosts to IPv4 addresses only
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// Resolve hosts to IPv6 addresses only
+    #[arg(short = '6', long)]
+    ipv6: bool,
+    /// Resolve hostnames again every DNS_TTL ms, 0 to resolve them only once
+    #[arg(long, default_value = "60000")]
+    dns_ttl: u64,
+    /// Ping every address a hostname resolves to
+    #[arg(long)]
+    all_addresses: bool,
     /// List of comma separated addresses to perform ICMP pings
     #[arg(long)]
     icmp: Option<String>,
@@ -26,56 +53,431 @@ struct Args {
     /// List of comma separated addresses to perform HTTP keepalive pings (default port 80)
     #[arg(long)]
     http: Option<String>,
+    /// HTTP method of HTTP pings
+    #[arg(long, default_value = "GET")]
+    http_method: String,
+    /// Path requested by HTTP pings
+    #[arg(long, default_value = "/")]
+    http_path: String,
+    /// Header added to HTTP pings, as "Name: value", can be repeated
+    #[arg(long = "http-header", value_name = "HEADER")]
+    http_headers: Vec<String>,
+    /// Body sent by HTTP pings, e.g. with --http-method POST
+    #[arg(long)]
+    http_data: Option<String>,
+    /// Only this HTTP status is successful, instead of any 2xx or 3xx status
+    #[arg(long)]
+    http_status: Option<u16>,
+    /// Text the body of successful HTTP responses contains
+    #[arg(long, conflicts_with = "http_body_regex")]
+    http_body: Option<String>,
+    /// Regular expression the body of successful HTTP responses matches
+    #[arg(long)]
+    http_body_regex: Option<String>,
+    /// Open a new connection for every HTTP ping instead of keeping it alive
+    #[arg(long)]
+    http_new_connection: bool,
+    /// List of comma separated addresses to perform HTTPS keepalive pings (default port 443)
+    #[arg(long)]
+    https: Option<String>,
+    /// List of comma separated addresses to measure TLS handshakes with (default port 443)
+    #[arg(long)]
+    tls: Option<String>,
+    /// Name sent as SNI and verified by the certificate, instead of the host of the address
+    #[arg(long)]
+    tls_server_name: Option<String>,
+    /// Accept any certificate, e.g. self-signed ones
+    #[arg(long)]
+    tls_insecure: bool,
+    /// Fail TLS and HTTPS pings when the certificate expires within this many days
+    #[arg(long, value_name = "DAYS")]
+    tls_expiry_days: Option<u32>,
     /// List of comma separated addresses to perform UDP pings (default port 8888)
     #[arg(long)]
     udp: Option<String>,
+    /// List of comma separated DNS resolver addresses to query (default port 53)
+    #[arg(long)]
+    dns: Option<String>,
+    /// Name to query DNS resolvers for
+    #[arg(long, default_value = "example.com")]
+    dns_name: String,
+    /// Record type to query DNS resolvers for (A, AAAA, MX, TXT, ...)
+    #[arg(long, default_value = "A")]
+    dns_type: MPingerDNSRecordType,
+    /// Format of the ping results and the summary printed to stdout
+    #[arg(short, long, value_enum, default_value = "text")]
+    output: OutputFormat,
+    /// Ping until stopped and serve Prometheus metrics at http://ADDRESS/metrics
+    #[arg(long, value_name = "ADDRESS")]
+    serve_metrics: Option<String>,
+    /// TOML or YAML file listing destinations, its settings take precedence over the flags
+    #[arg(long, value_name = "FILE")]
+    config: Option<String>,
 }
 
 struct PingStats {
     idx: usize,
     label: String,
     ping_type: String,
-    count: usize,
-    timeouts: usize,
-    min_ping: Option<u64>,
-    max_ping: Option<u64>,
-    avg_ping: RunningAverage,
+    // loss, RTT and jitter, the same numbers as any other frontend
+    stats: MPingerDestinationStats,
+    // average phases of HTTP pings
+    http: Option<HttpStats>,
+    // session of TLS and HTTPS pings
+    tls: Option<TlsStats>,
+    // replies of UDP pings which didn't match their probe
+    udp: Option<UdpStats>,
 }
 
-// Format duration in milliseconds
+struct HttpStats {
+    dns: RunningAverage,
+    connect: RunningAverage,
+    tls: RunningAverage,
+    request: RunningAverage,
+    first_byte: RunningAverage,
+    total: RunningAverage,
+    bytes_received: usize,
+    reconnects: usize,
+}
+
+impl HttpStats {
+    fn new() -> Self {
+        HttpStats {
+            dns: RunningAverage::new(MAX_AVG_PINGS),
+            connect: RunningAverage::new(MAX_AVG_PINGS),
+            tls: RunningAverage::new(MAX_AVG_PINGS),
+            request: RunningAverage::new(MAX_AVG_PINGS),
+            first_byte: RunningAverage::new(MAX_AVG_PINGS),
+            total: RunningAverage::new(MAX_AVG_PINGS),
+            bytes_received: 0,
+            reconnects: 0,
+        }
+    }
+
+    fn add(&mut self, details: &MPingerHttpDetails) {
+        if let Some(dns_time) = details.dns_time {
+            self.dns.add(dns_time.as_micros() as u64);
+        }
+        if let Some(connect_time) = details.connect_time {
+            self.connect.add(connect_time.as_micros() as u64);
+        }
+        if let Some(tls_time) = details.tls_time {
+            self.tls.add(tls_time.as_micros() as u64);
+        }
+        self.request.add(details.request_time.as_micros() as u64);
+        self.first_byte
+            .add(details.first_byte_time.as_micros() as u64);
+        self.total.add(details.total_time.as_micros() as u64);
+        self.bytes_received += details.bytes_received;
+        self.reconnects = self.reconnects.max(details.reconnects);
+    }
+}
+
+// Format duration given in microseconds as milliseconds
 fn format_duration_u64(duration: u64) -> String {
     format!("{:.2} ms", duration as f64 / 1_000.0)
 }
 
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1_000.0)
+}
+
 fn format_duration_f64(duration: f64) -> String {
     format!("{:.2} ms", duration / 1_000.0)
 }
 
+struct TlsStats {
+    version: String,
+    cipher: String,
+    connect: RunningAverage,
+    handshake: RunningAverage,
+    cert_not_after: Option<OffsetDateTime>,
+    cert_expires_in_days: Option<i64>,
+    chain_status: MPingerTlsChainStatus,
+}
+
+impl TlsStats {
+    fn new() -> Self {
+        TlsStats {
+            version: String::new(),
+            cipher: String::new(),
+            connect: RunningAverage::new(MAX_AVG_PINGS),
+            handshake: RunningAverage::new(MAX_AVG_PINGS),
+            cert_not_after: None,
+            cert_expires_in_days: None,
+            chain_status: MPingerTlsChainStatus::Unknown,
+        }
+    }
+
+    // the session of the last handshake is shown
+    fn add(&mut self, details: &MPingerTlsDetails) {
+        self.version = details.version.clone();
+        self.cipher = details.cipher.clone();
+        self.connect.add(details.connect_time.as_micros() as u64);
+        self.handshake
+            .add(details.handshake_time.as_micros() as u64);
+        self.cert_not_after = details.cert_not_after;
+        self.cert_expires_in_days = details.cert_expires_in_days;
+        self.chain_status = details.chain_status;
+    }
+}
+
+// averages, "-" if there is none, e.g. no ping opened a new connection
+fn format_average(average: &RunningAverage) -> String {
+    match average.get() {
+        Some(average) => format_duration_f64(average),
+        None => "-".to_string(),
+    }
+}
+
 fn print_stats(ping_stats: &[PingStats]) {
     let mut tp = TPrint::new(true, true, 0, 3);
 
     tp.column_add("Address", TPrintAlign::Center, TPrintAlign::Left)
         .column_add("Type", TPrintAlign::Center, TPrintAlign::Left)
         .column_add("Total pings", TPrintAlign::Center, TPrintAlign::Left)
-        .column_add("Timeouts", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Errors", TPrintAlign::Center, TPrintAlign::Left)
         .column_add("Min ping", TPrintAlign::Center, TPrintAlign::Left)
         .column_add("Max ping", TPrintAlign::Center, TPrintAlign::Left)
-        .column_add("Avg ping", TPrintAlign::Center, TPrintAlign::Left);
+        .column_add("Avg ping", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("p50", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("p90", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("p99", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("p99.9", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Std dev", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Jitter", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Loss", TPrintAlign::Center, TPrintAlign::Left);
 
     for ping_stat in ping_stats.iter() {
+        let stats = &ping_stat.stats;
         tp.add_data(&ping_stat.label)
             .add_data(&ping_stat.ping_type)
-            .add_data(ping_stat.count)
-            .add_data(ping_stat.timeouts)
-            .add_data(format_duration_u64(ping_stat.min_ping.unwrap_or(0)))
-            .add_data(format_duration_u64(ping_stat.max_ping.unwrap_or(0)))
-            .add_data(format_duration_f64(ping_stat.avg_ping.get().unwrap_or(0.0)));
+            .add_data(stats.pings)
+            .add_data(stats.errors)
+            .add_data(format_duration(stats.min_rtt.unwrap_or_default()))
+            .add_data(format_duration(stats.max_rtt.unwrap_or_default()))
+            .add_data(format_duration(stats.avg_rtt().unwrap_or_default()));
+        for percentile in [50.0, 90.0, 99.0, 99.9] {
+            tp.add_data(format_duration(
+                stats.percentile(percentile).unwrap_or_default(),
+            ));
+        }
+        tp.add_data(format_duration(stats.stddev().unwrap_or_default()))
+            .add_data(format_duration(stats.jitter().unwrap_or_default()))
+            .add_data(format!("{:.1}%", stats.loss()));
+    }
+    tp.print().unwrap();
+
+    if !ping_stats.iter().any(|ping_stat| ping_stat.http.is_some()) {
+        return;
+    }
+
+    // average time since the start of the ping at the end of each phase
+    let mut tp = TPrint::new(true, true, 0, 3);
+
+    tp.column_add("Address", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("DNS", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Connect", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("TLS", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Request sent", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("First byte", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Total", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Bytes received", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Reconnects", TPrintAlign::Center, TPrintAlign::Left);
+
+    for ping_stat in ping_stats.iter() {
+        if let Some(http) = ping_stat.http.as_ref() {
+            tp.add_data(&ping_stat.label)
+                .add_data(format_average(&http.dns))
+                .add_data(format_average(&http.connect))
+                .add_data(format_average(&http.tls))
+                .add_data(format_average(&http.request))
+                .add_data(format_average(&http.first_byte))
+                .add_data(format_average(&http.total))
+                .add_data(http.bytes_received)
+                .add_data(http.reconnects);
+        }
+    }
+    tp.print().unwrap();
+}
+
+struct UdpStats {
+    // only filled by reflectors stamping the probes
+    forward: OneWayDelay,
+    reverse: OneWayDelay,
+    reflector: RunningAverage,
+}
+
+impl UdpStats {
+    fn new() -> Self {
+        UdpStats {
+            forward: OneWayDelay::default(),
+            reverse: OneWayDelay::default(),
+            reflector: RunningAverage::new(MAX_AVG_PINGS),
+        }
+    }
+
+    fn add(&mut self, details: &MPingerUdpDetails) {
+        if let Some(forward_delay) = details.forward_delay {
+            self.forward.add(forward_delay);
+        }
+        if let Some(reverse_delay) = details.reverse_delay {
+            self.reverse.add(reverse_delay);
+        }
+        if let Some(reflector_time) = details.reflector_time {
+            self.reflector.add(reflector_time.as_micros() as u64);
+        }
+    }
+}
+
+// One-way delays in us, negative if the clocks of the hosts are apart
+#[derive(Default)]
+struct OneWayDelay {
+    sum: i64,
+    count: usize,
+    last: Option<i64>,
+    // interarrival jitter as in RFC 3550, smoothed over the last 16 or so probes
+    jitter: f64,
+}
+
+impl OneWayDelay {
+    fn add(&mut self, delay: time::Duration) {
+        let delay = delay.whole_microseconds() as i64;
+        if let Some(last) = self.last {
+            self.jitter += ((delay - last).abs() as f64 - self.jitter) / 16.0;
+        }
+        self.last = Some(delay);
+        self.sum += delay;
+        self.count += 1;
+    }
+
+    fn format_average(&self) -> String {
+        match self.count {
+            0 => "-".to_string(),
+            count => format_duration_f64(self.sum as f64 / count as f64),
+        }
+    }
+
+    fn format_jitter(&self) -> String {
+        match self.count {
+            0 => "-".to_string(),
+            _ => format_duration_f64(self.jitter),
+        }
+    }
+}
+
+fn print_udp_stats(ping_stats: &[PingStats]) {
+    if !ping_stats.iter().any(|ping_stat| ping_stat.udp.is_some()) {
+        return;
+    }
+
+    let mut tp = TPrint::new(true, true, 0, 3);
+
+    tp.column_add("Address", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Late replies", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Duplicates", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Reordered", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Forward", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Forward jitter", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Reverse", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Reverse jitter", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Reflector", TPrintAlign::Center, TPrintAlign::Left);
+
+    for ping_stat in ping_stats.iter() {
+        if let Some(udp) = ping_stat.udp.as_ref() {
+            tp.add_data(&ping_stat.label)
+                .add_data(ping_stat.stats.late_replies)
+                .add_data(ping_stat.stats.duplicate_replies)
+                .add_data(ping_stat.stats.reordered_replies)
+                .add_data(udp.forward.format_average())
+                .add_data(udp.forward.format_jitter())
+                .add_data(udp.reverse.format_average())
+                .add_data(udp.reverse.format_jitter())
+                .add_data(format_average(&udp.reflector));
+        }
+    }
+    tp.print().unwrap();
+}
+
+fn print_tls_stats(ping_stats: &[PingStats]) -> Result<()> {
+    if !ping_stats.iter().any(|ping_stat| ping_stat.tls.is_some()) {
+        return Ok(());
+    }
+
+    let format = format_description::parse_borrowed::<2>("[year]-[month]-[day]")?;
+    let mut tp = TPrint::new(true, true, 0, 3);
+
+    tp.column_add("Address", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Version", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Cipher", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Connect", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Handshake", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Certificate expiry", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Days left", TPrintAlign::Center, TPrintAlign::Left)
+        .column_add("Chain", TPrintAlign::Center, TPrintAlign::Left);
+
+    for ping_stat in ping_stats.iter() {
+        if let Some(tls) = ping_stat.tls.as_ref() {
+            let expiry = match tls.cert_not_after {
+                Some(not_after) => not_after.format(&format)?,
+                None => "-".to_string(),
+            };
+            let days_left = match tls.cert_expires_in_days {
+                Some(days) => days.to_string(),
+                None => "-".to_string(),
+            };
+            tp.add_data(&ping_stat.label)
+                .add_data(&tls.version)
+                .add_data(&tls.cipher)
+                .add_data(format_average(&tls.connect))
+                .add_data(format_average(&tls.handshake))
+                .add_data(expiry)
+                .add_data(days_left)
+                .add_data(tls.chain_status.to_string());
+        }
     }
     tp.print().unwrap();
+
+    Ok(())
 }
 
 const MAX_AVG_PINGS: usize = 100;
 
+// the same as the flags of the types
+fn probe_type_label(probe_type: MPingerProbeType) -> &'static str {
+    match probe_type {
+        MPingerProbeType::ICMP => "ICMP",
+        MPingerProbeType::Connect => "CONN",
+        MPingerProbeType::HTTP => "HTTP",
+        MPingerProbeType::HTTPS => "HTTPS",
+        MPingerProbeType::TLS => "TLS",
+        MPingerProbeType::UDP => "UDP",
+        MPingerProbeType::DNS => "DNS",
+    }
+}
+
+// the HTTP and TLS flags apply to the destinations of the file which use them,
+// unless their own options are set
+fn apply_flags(
+    dest: &mut MPingerDestinationConfig,
+    http_request: &MPingerHttpRequest,
+    tls_options: &MPingerTlsOptions,
+) {
+    if matches!(
+        dest.probe_type,
+        MPingerProbeType::HTTP | MPingerProbeType::HTTPS
+    ) {
+        dest.http_request
+            .get_or_insert_with(|| http_request.clone());
+    }
+    if matches!(
+        dest.probe_type,
+        MPingerProbeType::HTTPS | MPingerProbeType::TLS
+    ) {
+        dest.tls.get_or_insert_with(|| tls_options.clone());
+    }
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
@@ -89,13 +491,34 @@ fn main() -> Result<()> {
             .init();
     }
 
-    let conf = MPingerConfig {
+    let ip_family = if args.ipv4 {
+        MPingerIpFamily::V4
+    } else if args.ipv6 {
+        MPingerIpFamily::V6
+    } else {
+        MPingerIpFamily::Any
+    };
+
+    let mut conf = MPingerConfig {
+        ping_interval: args.interval,
+        ping_retries: args.retries,
         timeout: 1000,
-        next_timeout: 3000,
+        ip_family,
+        dns_ttl: args.dns_ttl,
+        resolve_all: args.all_addresses,
+        dns_query_name: args.dns_name,
+        dns_query_type: args.dns_type,
         ..Default::default()
     };
+    let config_file = match args.config.as_ref() {
+        Some(path) => {
+            let config_file = MPingerConfigFile::load(path)?;
+            config_file.settings.apply(&mut conf)?;
+            Some(config_file)
+        }
+        None => None,
+    };
     let mut ping_cli = MPinger::new(conf);
-    ping_cli.set_ping_interval(args.interval);
 
     let mut total_addresses = 0;
 
@@ -110,11 +533,10 @@ fn main() -> Result<()> {
                 idx: id,
                 label: address.to_string(),
                 ping_type: "ICMP".to_string(),
-                count: 0,
-                timeouts: 0,
-                min_ping: None,
-                max_ping: None,
-                avg_ping: RunningAverage::new(MAX_AVG_PINGS),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
             });
             total_addresses += 1;
         }
@@ -129,30 +551,103 @@ fn main() -> Result<()> {
                 idx: id,
                 label: address.to_string(),
                 ping_type: "CONN".to_string(),
-                count: 0,
-                timeouts: 0,
-                min_ping: None,
-                max_ping: None,
-                avg_ping: RunningAverage::new(MAX_AVG_PINGS),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
             });
             total_addresses += 1;
         }
     }
 
+    let mut headers = Vec::new();
+    for header in args.http_headers.iter() {
+        match header.split_once(':') {
+            Some((name, value)) => {
+                headers.push((name.trim().to_string(), value.trim().to_string()))
+            }
+            None => return Err(anyhow::anyhow!("Invalid HTTP header: {}", header)),
+        }
+    }
+    let expected_body = match (args.http_body, args.http_body_regex) {
+        (Some(text), _) => Some(MPingerHttpBodyMatch::Contains(text)),
+        (None, Some(pattern)) => Some(MPingerHttpBodyMatch::regex(&pattern)?),
+        (None, None) => None,
+    };
+    let http_request = MPingerHttpRequest {
+        method: args.http_method,
+        path: args.http_path,
+        headers,
+        body: args.http_data,
+        expected_status: args.http_status,
+        expected_body,
+        new_connection: args.http_new_connection,
+    };
+
     if let Some(http) = args.http {
         let http_addresses: Vec<&str> = http.split(',').collect();
         for address in http_addresses {
-            let id = ping_cli.add_destination(MPingerType::HTTPKeepAlive, address)?;
+            let id = ping_cli.add_http_destination(address, http_request.clone())?;
 
             ping_stats.push(PingStats {
                 idx: id,
                 label: address.to_string(),
                 ping_type: "HTTP".to_string(),
-                count: 0,
-                timeouts: 0,
-                min_ping: None,
-                max_ping: None,
-                avg_ping: RunningAverage::new(MAX_AVG_PINGS),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
+            });
+            total_addresses += 1;
+        }
+    }
+
+    let tls_options = MPingerTlsOptions {
+        server_name: args.tls_server_name,
+        skip_verify: args.tls_insecure,
+        expiry_warning_days: args.tls_expiry_days,
+        ..Default::default()
+    };
+
+    if let Some(https) = args.https {
+        let https_addresses: Vec<&str> = https.split(',').collect();
+        for address in https_addresses {
+            let id = ping_cli.add_https_destination(
+                address,
+                http_request.clone(),
+                tls_options.clone(),
+            )?;
+
+            ping_stats.push(PingStats {
+                idx: id,
+                label: address.to_string(),
+                ping_type: "HTTPS".to_string(),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
+            });
+            total_addresses += 1;
+        }
+    }
+
+    if let Some(tls) = args.tls {
+        let tls_addresses: Vec<&str> = tls.split(',').collect();
+        for address in tls_addresses {
+            let id = ping_cli.add_tls_destination(
+                MPingerType::TLSHandshake,
+                address,
+                tls_options.clone(),
+            )?;
+
+            ping_stats.push(PingStats {
+                idx: id,
+                label: address.to_string(),
+                ping_type: "TLS".to_string(),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
             });
             total_addresses += 1;
         }
@@ -167,11 +662,46 @@ fn main() -> Result<()> {
                 idx: id,
                 label: address.to_string(),
                 ping_type: "UDP".to_string(),
-                count: 0,
-                timeouts: 0,
-                min_ping: None,
-                max_ping: None,
-                avg_ping: RunningAverage::new(MAX_AVG_PINGS),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
+            });
+            total_addresses += 1;
+        }
+    }
+
+    if let Some(dns) = args.dns {
+        let dns_addresses: Vec<&str> = dns.split(',').collect();
+        for address in dns_addresses {
+            let id = ping_cli.add_destination(MPingerType::DNSQuery, address)?;
+
+            ping_stats.push(PingStats {
+                idx: id,
+                label: address.to_string(),
+                ping_type: "DNS".to_string(),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
+            });
+            total_addresses += 1;
+        }
+    }
+
+    if let Some(config_file) = config_file {
+        for mut dest in config_file.destinations {
+            apply_flags(&mut dest, &http_request, &tls_options);
+            let id = ping_cli.add_destination_config(&dest)?;
+
+            ping_stats.push(PingStats {
+                idx: id,
+                label: dest.name.unwrap_or(dest.address),
+                ping_type: probe_type_label(dest.probe_type).to_string(),
+                stats: MPingerDestinationStats::default(),
+                http: None,
+                tls: None,
+                udp: None,
             });
             total_addresses += 1;
         }
@@ -182,19 +712,34 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let ping_stats = Arc::new(Mutex::new(ping_stats));
-    let ping_stats_clone = Arc::clone(&ping_stats);
-    ctrlc::set_handler(move || {
-        let ping_stats = ping_stats_clone.lock().unwrap();
-        println!();
+    let mut output = Output::new(args.output);
+    // the exporter pings until it is stopped
+    let metrics = match args.serve_metrics.as_ref() {
+        Some(address) => {
+            let metrics = Arc::new(Mutex::new(Metrics::default()));
+            metrics::serve(address, metrics.clone())?;
+            Some(metrics)
+        }
+        None => None,
+    };
+    let count = match metrics {
+        Some(_) => 0,
+        None => args.count,
+    };
 
-        print_stats(&ping_stats);
-        process::exit(0);
+    let pinger_reader = ping_cli.start(count);
+
+    // stop gracefully, the summary is printed once the runners are done
+    let handle = pinger_reader.get_handle();
+    let text_output = args.output == OutputFormat::Text;
+    ctrlc::set_handler(move || {
+        if text_output {
+            println!();
+        }
+        handle.stop();
     })
     .expect("Error setting Ctrl+C handler");
 
-    let pinger_reader = ping_cli.start(args.count);
-
     /*
     let rx = pinger_reader.get_rx();
     let mut iter = rx.try_iter();
@@ -206,7 +751,7 @@ fn main() -> Result<()> {
                 break;
             }
             let ping_message = val.unwrap();
-            if ping_message.is_error {
+            if ping_message.is_error() {
                 continue;
             }
             println!("Ping message: {:?}", ping_message);
@@ -217,48 +762,235 @@ fn main() -> Result<()> {
     */
 
     for ping_message in pinger_reader {
-        let date: OffsetDateTime =
-            OffsetDateTime::from_unix_timestamp(ping_message.start_timestamp)?;
-        let format = format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]")?;
-        let date = date.format(&format)?;
-
-        println!(
-            "[{}] [{}] [{}] {}: {} ms",
-            date,
-            ping_cli.get_runner_description(&ping_message.runner_type),
-            ping_message.ping_nr + 1,
-            ping_cli
-                .get_destination_by_id(ping_message.destination_id)
-                .unwrap()
-                .address,
-            ping_message.duration
-        );
+        let format = format_description::parse_borrowed::<2>(
+            "[year]-[month]-[day] [hour]:[minute]:[second].[subsecond digits:3]",
+        )?;
+        let date = ping_message.send_time.format(&format)?;
+
+        let dest = match ping_cli.get_destination_by_id(ping_message.destination_id) {
+            Some(dest) => dest,
+            None => continue,
+        };
+        // other addresses of a hostname are shown next to it
+        let name = dest.name.as_ref().unwrap_or(&dest.address);
+        let label = match dest.parent_id {
+            Some(_) => format!("{} ({})", name, dest.ip()),
+            None => name.clone(),
+        };
+
+        match ping_message.kind {
+            MPingerMessageKind::Ping => {}
+            MPingerMessageKind::DestinationStarted => {
+                let parent_stat = dest
+                    .parent_id
+                    .and_then(|parent_id| ping_stats.iter().find(|stat| stat.idx == parent_id));
+                let is_new = !ping_stats.iter().any(|stat| stat.idx == dest.id);
+                if let (Some(parent_stat), true) = (parent_stat, is_new) {
+                    let ping_type = parent_stat.ping_type.clone();
+                    ping_stats.push(PingStats {
+                        idx: dest.id,
+                        label,
+                        ping_type,
+                        stats: MPingerDestinationStats::default(),
+                        http: None,
+                        tls: None,
+                        udp: None,
+                    });
+                }
+                continue;
+            }
+            MPingerMessageKind::DestinationStopped => continue,
+            MPingerMessageKind::AddressChanged { old, new } => {
+                // stdout only carries records in the other formats
+                let line = format!(
+                    "[{}] {} changed address from {} to {}",
+                    date, label, old, new
+                );
+                if text_output {
+                    println!("{}", line);
+                } else {
+                    eprintln!("{}", line);
+                }
+                continue;
+            }
+            MPingerMessageKind::ResolveFailed => {
+                let line = format!(
+                    "[{}] {}: {}, keeping {}",
+                    date,
+                    label,
+                    ping_message.result,
+                    dest.ip()
+                );
+                if text_output {
+                    println!("{}", line);
+                } else {
+                    eprintln!("{}", line);
+                }
+                continue;
+            }
+        }
+
+        if text_output {
+            let duration = ping_message.rtt.as_micros() as u64;
+            let result = if ping_message.is_error() && duration > 0 {
+                // a response was received, e.g. an HTTP error status or a DNS rcode
+                format!(
+                    "{} ({})",
+                    ping_message.result,
+                    format_duration_u64(duration)
+                )
+            } else if ping_message.is_error() {
+                ping_message.result.to_string()
+            } else {
+                format_duration_u64(duration)
+            };
+
+            println!(
+                "[{}] [{}] [{}] {}: {}",
+                date,
+                ping_cli.get_runner_description(&ping_message.runner_type),
+                ping_message.ping_nr + 1,
+                label,
+                result
+            );
+        } else {
+            let ping_type = ping_stats
+                .iter()
+                .find(|stat| stat.idx == ping_message.destination_id)
+                .map(|stat| stat.ping_type.as_str())
+                .unwrap_or_default();
+            output.ping(&PingRecord {
+                record: "ping",
+                time: ping_message.send_time.format(&Rfc3339)?,
+                destination: &label,
+                address: dest.ip().to_string(),
+                ping_type,
+                tags: dest.tags.join(","),
+                seq: ping_message.ping_nr + 1,
+                success: !ping_message.is_error(),
+                result: ping_message.result.to_string(),
+                // also set for failed pings with a response
+                rtt_ms: (!ping_message.rtt.is_zero()).then(|| to_ms(ping_message.rtt)),
+            })?;
+        }
 
-        let mut ps = ping_stats.lock().unwrap();
-        if let Some(stat) = ps
+        if let Some(stat) = ping_stats
             .iter_mut()
             .find(|stat| stat.idx == ping_message.destination_id)
         {
-            stat.count += 1;
-
-            if ping_message.is_error || ping_message.duration == 0 {
-                stat.timeouts += 1;
-            } else {
-                stat.avg_ping.add(ping_message.duration);
-            }
-
-            if stat.min_ping.is_none() || Some(ping_message.duration) < stat.min_ping {
-                stat.min_ping = Some(ping_message.duration);
+            stat.stats.add(&ping_message);
+            if let Some(metrics) = metrics.as_ref() {
+                metrics
+                    .lock()
+                    .unwrap()
+                    .add(&ping_message, &dest, &stat.label);
             }
 
-            if stat.max_ping.is_none() || Some(ping_message.duration) > stat.max_ping {
-                stat.max_ping = Some(ping_message.duration);
+            // phases of HTTP responses, whatever their status
+            match &ping_message.details {
+                MPingerDetails::Http(details) => {
+                    stat.http.get_or_insert_with(HttpStats::new).add(details);
+                    // HTTPS responses carry the session of their connection, only count new ones
+                    if let (Some(tls), Some(_)) = (&details.tls, details.tls_time) {
+                        stat.tls.get_or_insert_with(TlsStats::new).add(tls);
+                    }
+                }
+                MPingerDetails::Tls(details) => {
+                    stat.tls.get_or_insert_with(TlsStats::new).add(details);
+                }
+                MPingerDetails::Udp(details) => {
+                    stat.udp.get_or_insert_with(UdpStats::new).add(details);
+                }
+                MPingerDetails::None => {}
             }
         }
     }
 
-    let ping_stats = ping_stats.lock().unwrap();
+    if !text_output {
+        let records: Vec<SummaryRecord> = ping_stats
+            .iter()
+            .map(|stat| {
+                let tags = ping_cli
+                    .get_destination_by_id(stat.idx)
+                    .map(|dest| dest.tags.join(","))
+                    .unwrap_or_default();
+                SummaryRecord::new(&stat.label, &stat.ping_type, tags, &stat.stats)
+            })
+            .collect();
+        return output.summary(&records);
+    }
+
     print_stats(&ping_stats);
+    print_tls_stats(&ping_stats)?;
+    print_udp_stats(&ping_stats);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destination(probe_type: &str) -> MPingerDestinationConfig {
+        let file = format!(
+            "[[destinations]]\ntype = \"{}\"\naddress = \"192.0.2.1\"",
+            probe_type
+        );
+        MPingerConfigFile::from_toml(&file)
+            .unwrap()
+            .destinations
+            .remove(0)
+    }
+
+    #[test]
+    fn flags_of_the_destinations_using_them() {
+        let http_request = MPingerHttpRequest {
+            path: "/health".to_string(),
+            ..Default::default()
+        };
+        let tls_options = MPingerTlsOptions {
+            skip_verify: true,
+            ..Default::default()
+        };
+
+        let uses = [
+            ("icmp", false, false),
+            ("connect", false, false),
+            ("udp", false, false),
+            ("dns", false, false),
+            ("http", true, false),
+            ("https", true, true),
+            ("tls", false, true),
+        ];
+        for (probe_type, http, tls) in uses {
+            let mut dest = destination(probe_type);
+            apply_flags(&mut dest, &http_request, &tls_options);
+            assert_eq!(dest.http_request.is_some(), http, "{}", probe_type);
+            assert_eq!(dest.tls.is_some(), tls, "{}", probe_type);
+        }
+    }
+
+    #[test]
+    fn options_of_the_destination_are_kept() {
+        let mut dest = destination("https");
+        dest.http_request = Some(MPingerHttpRequest {
+            path: "/status".to_string(),
+            ..Default::default()
+        });
+        dest.tls = Some(MPingerTlsOptions {
+            server_name: Some("example.test".to_string()),
+            ..Default::default()
+        });
+
+        apply_flags(
+            &mut dest,
+            &MPingerHttpRequest::default(),
+            &MPingerTlsOptions::default(),
+        );
+        assert_eq!(dest.http_request.unwrap().path, "/status");
+        assert_eq!(
+            dest.tls.unwrap().server_name.as_deref(),
+            Some("example.test")
+        );
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::Result;
+use log::{debug, error, info};
+use mpinger::{MPingDestination, MPingerDestinationStats, MPingerMessage};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+// upper bounds of the RTT histogram buckets in seconds, +Inf is added
+const RTT_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Prometheus metrics of the destinations, updated with every ping message
+#[derive(Default)]
+pub struct Metrics {
+    destinations: BTreeMap<usize, DestinationMetrics>,
+}
+
+struct DestinationMetrics {
+    // current IP address, and the name or address given for the destination
+    address: String,
+    name: String,
+    ping_type: String,
+    stats: MPingerDestinationStats,
+    // cumulative counts of successful pings per bucket
+    rtt_buckets: [u64; RTT_BUCKETS.len()],
+    rtt_sum: f64,
+    last_success: Option<OffsetDateTime>,
+}
+
+impl Metrics {
+    pub fn add(&mut self, message: &MPingerMessage, dest: &MPingDestination, name: &str) {
+        let address = dest.ip().to_string();
+        let dest = self
+            .destinations
+            .entry(message.destination_id)
+            .or_insert_with(|| DestinationMetrics {
+                address: String::new(),
+                name: name.to_string(),
+                ping_type: format!("{:?}", message.runner_type),
+                stats: MPingerDestinationStats::default(),
+                rtt_buckets: [0; RTT_BUCKETS.len()],
+                rtt_sum: 0.0,
+                last_success: None,
+            });
+
+        // follows address changes of hostnames
+        dest.address = address;
+        dest.stats.add(message);
+        if message.is_error() {
+            return;
+        }
+
+        let rtt = message.rtt.as_secs_f64();
+        for (bucket, le) in dest.rtt_buckets.iter_mut().zip(RTT_BUCKETS) {
+            if rtt <= le {
+                *bucket += 1;
+            }
+        }
+        dest.rtt_sum += rtt;
+        dest.last_success = Some(message.send_time);
+    }
+
+    // text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        self.write_counter(&mut out, "mpinger_pings_total", "Pings sent", |stats| {
+            stats.pings
+        });
+        self.write_counter(&mut out, "mpinger_errors_total", "Failed pings", |stats| {
+            stats.errors
+        });
+        self.write_counter(
+            &mut out,
+            "mpinger_lost_total",
+            "Pings without any response",
+            |stats| stats.lost,
+        );
+
+        let name = "mpinger_rtt_seconds";
+        let _ = writeln!(out, "# HELP {} Round trip time of successful pings", name);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for dest in self.destinations.values() {
+            let labels = dest.labels();
+            for (count, le) in dest.rtt_buckets.iter().zip(RTT_BUCKETS) {
+                let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, le, count);
+            }
+            let count = dest.stats.rtt_histogram.count();
+            let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+            let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, dest.rtt_sum);
+            let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+        }
+
+        let name = "mpinger_last_success_timestamp_seconds";
+        let _ = writeln!(out, "# HELP {} Send time of the last successful ping", name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for dest in self.destinations.values() {
+            if let Some(last_success) = dest.last_success {
+                let timestamp = last_success.unix_timestamp_nanos() as f64 / 1_000_000_000.0;
+                let _ = writeln!(out, "{}{{{}}} {}", name, dest.labels(), timestamp);
+            }
+        }
+
+        out
+    }
+
+    fn write_counter<F>(&self, out: &mut String, name: &str, help: &str, value: F)
+    where
+        F: Fn(&MPingerDestinationStats) -> usize,
+    {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for dest in self.destinations.values() {
+            let _ = writeln!(out, "{}{{{}}} {}", name, dest.labels(), value(&dest.stats));
+        }
+    }
+}
+
+impl DestinationMetrics {
+    fn labels(&self) -> String {
+        format!(
+            "address=\"{}\",name=\"{}\",type=\"{}\"",
+            escape_label(&self.address),
+            escape_label(&self.name),
+            escape_label(&self.ping_type)
+        )
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// serves GET /metrics on a background thread until the process exits
+pub fn serve(address: &str, metrics: Arc<Mutex<Metrics>>) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_request(stream, &metrics) {
+                        debug!("Error serving metrics: {}", e);
+                    }
+                }
+                Err(e) => error!("Error accepting metrics connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream, metrics: &Mutex<Metrics>) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // only the request line matters, the headers are read until the empty line
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer)?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.lock().unwrap().render()),
+        _ => ("404 Not Found", "Not found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mpinger::{MPinger, MPingerConfig, MPingerResult, MPingerType};
+
+    #[test]
+    fn labels() {
+        let mut pinger = MPinger::new(MPingerConfig::default());
+        let id = pinger
+            .add_destination(MPingerType::Rnd, "192.0.2.7:80")
+            .unwrap();
+        let dest = pinger.get_destination_by_id(id).unwrap();
+
+        let mut metrics = Metrics::default();
+        let ping = |rtt_ms, result| {
+            MPingerMessage::new_ping(
+                &dest,
+                0,
+                OffsetDateTime::now_utc(),
+                Duration::from_millis(rtt_ms),
+                result,
+            )
+        };
+        metrics.add(&ping(20, MPingerResult::Success), &dest, "web \"1\"");
+        metrics.add(&ping(0, MPingerResult::Timeout), &dest, "web \"1\"");
+
+        let labels = "address=\"192.0.2.7\",name=\"web \\\"1\\\"\",type=\"Rnd\"";
+        let text = metrics.render();
+        for line in [
+            format!("mpinger_pings_total{{{}}} 2", labels),
+            format!("mpinger_errors_total{{{}}} 1", labels),
+            format!("mpinger_lost_total{{{}}} 1", labels),
+            format!("mpinger_rtt_seconds_bucket{{{},le=\"0.01\"}} 0", labels),
+            format!("mpinger_rtt_seconds_bucket{{{},le=\"0.025\"}} 1", labels),
+            format!("mpinger_rtt_seconds_count{{{}}} 1", labels),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{}\n{}", line, text);
+        }
+    }
+}
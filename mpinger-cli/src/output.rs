@@ -0,0 +1,317 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use mpinger::MPingerDestinationStats;
+use serde::Serialize;
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Log lines and summary tables
+    Text,
+    /// One JSON object per line
+    Json,
+    /// Pings, then the summary with its own header after an empty line
+    Csv,
+}
+
+// One ping result
+#[derive(Serialize)]
+pub struct PingRecord<'a> {
+    pub record: &'static str,
+    // RFC 3339 send time
+    pub time: String,
+    pub destination: &'a str,
+    pub address: String,
+    pub ping_type: &'a str,
+    // comma separated tags of the config file
+    pub tags: String,
+    pub seq: usize,
+    pub success: bool,
+    pub result: String,
+    // empty if no response was received
+    pub rtt_ms: Option<f64>,
+}
+
+// Statistics of a destination, written once all pings are done
+#[derive(Serialize)]
+pub struct SummaryRecord<'a> {
+    pub record: &'static str,
+    pub destination: &'a str,
+    pub ping_type: &'a str,
+    pub tags: String,
+    pub pings: usize,
+    pub errors: usize,
+    pub lost: usize,
+    pub loss_percent: f64,
+    pub min_ms: Option<f64>,
+    pub max_ms: Option<f64>,
+    pub avg_ms: Option<f64>,
+    pub p50_ms: Option<f64>,
+    pub p90_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+    pub p99_9_ms: Option<f64>,
+    pub stddev_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+}
+
+impl<'a> SummaryRecord<'a> {
+    pub fn new(
+        destination: &'a str,
+        ping_type: &'a str,
+        tags: String,
+        stats: &MPingerDestinationStats,
+    ) -> Self {
+        SummaryRecord {
+            record: "summary",
+            destination,
+            ping_type,
+            tags,
+            pings: stats.pings,
+            errors: stats.errors,
+            lost: stats.lost,
+            loss_percent: stats.loss(),
+            min_ms: stats.min_rtt.map(to_ms),
+            max_ms: stats.max_rtt.map(to_ms),
+            avg_ms: stats.avg_rtt().map(to_ms),
+            p50_ms: stats.percentile(50.0).map(to_ms),
+            p90_ms: stats.percentile(90.0).map(to_ms),
+            p99_ms: stats.percentile(99.0).map(to_ms),
+            p99_9_ms: stats.percentile(99.9).map(to_ms),
+            stddev_ms: stats.stddev().map(to_ms),
+            jitter_ms: stats.jitter().map(to_ms),
+        }
+    }
+}
+
+// in us precision, as the text output
+pub fn to_ms(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1_000.0
+}
+
+// Writes the records to stdout in the JSON or CSV format
+pub struct Output<W: Write = std::io::Stdout> {
+    format: OutputFormat,
+    out: W,
+    // the header is written with the first ping
+    csv_header_written: bool,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Output::with_writer(format, std::io::stdout())
+    }
+}
+
+impl<W: Write> Output<W> {
+    pub fn with_writer(format: OutputFormat, out: W) -> Self {
+        Output {
+            format,
+            out,
+            csv_header_written: false,
+        }
+    }
+
+    pub fn ping(&mut self, record: &PingRecord) -> Result<()> {
+        match self.format {
+            OutputFormat::Json => writeln!(self.out, "{}", serde_json::to_string(record)?)?,
+            OutputFormat::Csv => {
+                let mut csv = csv::WriterBuilder::new()
+                    .has_headers(!self.csv_header_written)
+                    .from_writer(&mut self.out);
+                csv.serialize(record)?;
+                csv.flush()?;
+                self.csv_header_written = true;
+            }
+            OutputFormat::Text => return Ok(()),
+        }
+        // records show up as they come, e.g. when piped
+        self.out.flush()?;
+
+        Ok(())
+    }
+
+    pub fn summary(&mut self, records: &[SummaryRecord]) -> Result<()> {
+        match self.format {
+            OutputFormat::Json => {
+                for record in records {
+                    writeln!(self.out, "{}", serde_json::to_string(record)?)?;
+                }
+            }
+            OutputFormat::Csv => {
+                writeln!(self.out)?;
+
+                let mut csv = csv::Writer::from_writer(&mut self.out);
+                for record in records {
+                    csv.serialize(record)?;
+                }
+                csv.flush()?;
+            }
+            OutputFormat::Text => {}
+        }
+        self.out.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    const DESTINATION: &str = "web, \"main\"\nsite";
+
+    fn ping_record(success: bool) -> PingRecord<'static> {
+        PingRecord {
+            record: "ping",
+            time: "2025-01-02T03:04:05.123456Z".to_string(),
+            destination: DESTINATION,
+            address: "192.0.2.1:443".to_string(),
+            ping_type: "HTTPS",
+            tags: "eu,prod".to_string(),
+            seq: 1,
+            success,
+            result: if success { "Success" } else { "Timeout" }.to_string(),
+            rtt_ms: if success { Some(12.345) } else { None },
+        }
+    }
+
+    fn summary_record() -> SummaryRecord<'static> {
+        let mut stats = MPingerDestinationStats::default();
+        stats.pings = 2;
+        stats.errors = 1;
+        stats.lost = 1;
+        SummaryRecord::new(DESTINATION, "HTTPS", "eu,prod".to_string(), &stats)
+    }
+
+    fn write(format: OutputFormat) -> String {
+        let mut output = Output::with_writer(format, Vec::new());
+        output.ping(&ping_record(true)).unwrap();
+        output.ping(&ping_record(false)).unwrap();
+        output.summary(&[summary_record()]).unwrap();
+        String::from_utf8(output.out).unwrap()
+    }
+
+    fn keys(value: &Value) -> Vec<&str> {
+        value
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|k| k.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn csv_escaping() {
+        let text = write(OutputFormat::Csv);
+        let (pings, summary) = text.split_once("\n\n").unwrap();
+
+        // fields with commas, quotes or newlines are quoted, quotes are doubled
+        assert!(pings.contains("\"web, \"\"main\"\"\nsite\""), "{}", pings);
+        assert!(pings.contains(",\"eu,prod\","), "{}", pings);
+
+        let mut reader = csv::Reader::from_reader(pings.as_bytes());
+        let header: Vec<String> = reader.headers().unwrap().iter().map(String::from).collect();
+        assert_eq!(
+            header,
+            [
+                "record",
+                "time",
+                "destination",
+                "address",
+                "ping_type",
+                "tags",
+                "seq",
+                "success",
+                "result",
+                "rtt_ms"
+            ]
+        );
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(&rows[0][2], DESTINATION);
+        assert_eq!(&rows[0][5], "eu,prod");
+        assert_eq!(&rows[0][9], "12.345");
+        // no response, no RTT
+        assert_eq!(&rows[1][7], "false");
+        assert_eq!(&rows[1][9], "");
+
+        // the summary has its own header
+        let mut reader = csv::Reader::from_reader(summary.as_bytes());
+        assert_eq!(&reader.headers().unwrap()[0], "record");
+        assert_eq!(&reader.headers().unwrap()[8], "min_ms");
+        let rows: Vec<csv::StringRecord> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(&rows[0][0], "summary");
+        assert_eq!(&rows[0][1], DESTINATION);
+        assert_eq!(&rows[0][7], "50.0");
+    }
+
+    #[test]
+    fn json_lines_schema() {
+        let text = write(OutputFormat::Json);
+        let lines: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+
+        let ping = &lines[0];
+        assert_eq!(
+            keys(ping),
+            [
+                "address",
+                "destination",
+                "ping_type",
+                "record",
+                "result",
+                "rtt_ms",
+                "seq",
+                "success",
+                "tags",
+                "time"
+            ]
+        );
+        assert_eq!(ping["record"], "ping");
+        assert_eq!(ping["destination"], DESTINATION);
+        assert_eq!(ping["time"], "2025-01-02T03:04:05.123456Z");
+        assert_eq!(ping["seq"], 1);
+        assert_eq!(ping["success"], true);
+        assert_eq!(ping["rtt_ms"], 12.345);
+        assert_eq!(lines[1]["rtt_ms"], Value::Null);
+
+        let summary = &lines[2];
+        assert_eq!(
+            keys(summary),
+            [
+                "avg_ms",
+                "destination",
+                "errors",
+                "jitter_ms",
+                "loss_percent",
+                "lost",
+                "max_ms",
+                "min_ms",
+                "p50_ms",
+                "p90_ms",
+                "p99_9_ms",
+                "p99_ms",
+                "ping_type",
+                "pings",
+                "record",
+                "stddev_ms",
+                "tags"
+            ]
+        );
+        assert_eq!(summary["record"], "summary");
+        assert_eq!(summary["pings"], 2);
+        assert_eq!(summary["loss_percent"], 50.0);
+        assert_eq!(summary["avg_ms"], Value::Null);
+    }
+
+    #[test]
+    fn text_writes_nothing() {
+        assert_eq!(write(OutputFormat::Text), "");
+    }
+}
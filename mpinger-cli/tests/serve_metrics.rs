@@ -0,0 +1,78 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+// kills the exporter when the test is over, even if it fails
+struct Exporter(Child);
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn get_metrics(address: &str) -> String {
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    write!(
+        stream,
+        "GET /metrics HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        address
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+fn pings_total(metrics: &str) -> usize {
+    metrics
+        .lines()
+        .find(|line| line.starts_with("mpinger_pings_total{"))
+        .and_then(|line| line.rsplit(' ').next())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+// readers used to give up after next_timeout ms without a message, 5000 by default in the
+// library and set to 3000 by the CLI, which ended the exporter between two pings
+#[test]
+fn exporter_keeps_running_with_long_interval() {
+    let child = Command::new(env!("CARGO_BIN_EXE_mpinger-cli"))
+        .args([
+            "--connect",
+            "127.0.0.1:1",
+            "-i",
+            "5000",
+            "--serve-metrics",
+            "127.0.0.1:0",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut exporter = Exporter(child);
+
+    let mut stderr = BufReader::new(exporter.0.stderr.take().unwrap());
+    let mut line = String::new();
+    let address = loop {
+        line.clear();
+        assert!(stderr.read_line(&mut line).unwrap() > 0, "exporter exited");
+        if let Some((_, url)) = line.split_once("Serving metrics on http://") {
+            break url.trim().trim_end_matches("/metrics").to_string();
+        }
+    };
+
+    // the second ping is sent after 5 s without any message
+    std::thread::sleep(Duration::from_secs(6));
+    assert!(exporter.0.try_wait().unwrap().is_none(), "exporter exited");
+
+    let metrics = get_metrics(&address);
+    assert!(metrics.starts_with("HTTP/1.1 200"), "{}", metrics);
+    assert!(pings_total(&metrics) >= 2, "{}", metrics);
+}
@@ -1,10 +1,38 @@
 mod mpinger;
+#[cfg(feature = "tokio")]
+mod mpinger_async;
+#[cfg(feature = "config")]
+mod mpinger_config_file;
+mod mpinger_dns_query;
 mod mpinger_http_keepalive;
 mod mpinger_icmp;
+mod mpinger_registry;
 mod mpinger_rnd;
+mod mpinger_runner;
+mod mpinger_session;
+mod mpinger_stats;
 mod mpinger_tcp_connect;
+#[cfg(feature = "tls")]
+mod mpinger_tls;
 mod mpinger_udp;
 mod utils;
 
-pub use crate::mpinger::{MPinger, MPingerConfig, MPingerMessage, MPingerReader, MPingerType};
-pub use crate::utils::RunningAverage;
+pub use crate::mpinger::{
+    MPingDestination, MPinger, MPingerConfig, MPingerConfigOverrides, MPingerConfigShared,
+    MPingerDNSRecordType, MPingerDetails, MPingerHandle, MPingerHttpBodyMatch, MPingerHttpDetails,
+    MPingerHttpRequest, MPingerIpFamily, MPingerMessage, MPingerMessageKind, MPingerReader,
+    MPingerResult, MPingerRunners, MPingerTlsChainStatus, MPingerTlsDetails, MPingerTlsOptions,
+    MPingerType, MPingerUdpDetails,
+};
+#[cfg(feature = "tokio")]
+pub use crate::mpinger_async::MPingerStream;
+#[cfg(feature = "config")]
+pub use crate::mpinger_config_file::{
+    MPingerConfigFile, MPingerDestinationConfig, MPingerProbeType, MPingerSettings,
+};
+#[cfg(feature = "tokio")]
+pub use crate::mpinger_runner::RunnerFuture;
+pub use crate::mpinger_runner::{MPingerStopSignal, Runner};
+pub use crate::mpinger_stats::{MPingerDestinationStats, MPingerStats};
+pub use crate::mpinger_udp::MPingerUdpPacket;
+pub use crate::utils::{Histogram, RunningAverage};
@@ -1,43 +1,432 @@
+#[cfg(feature = "config")]
+use crate::mpinger_config_file::{MPingerConfigFile, MPingerDestinationConfig};
+#[cfg(feature = "tls")]
+use crate::mpinger_tls::{MPingerHTTPSKeepAlive, MPingerTLS};
 use crate::{
-    mpinger_http_keepalive::MPingerHTTPKeepAlive, mpinger_icmp::MPingerICMP,
-    mpinger_rnd::MPingerRnd, mpinger_tcp_connect::MPingerTCPConnect, mpinger_udp::MPingerUDP,
-    utils,
+    mpinger_dns_query::MPingerDNSQuery, mpinger_http_keepalive::MPingerHTTPKeepAlive,
+    mpinger_icmp::MPingerICMP, mpinger_registry::MPingerRegistry, mpinger_rnd::MPingerRnd,
+    mpinger_runner::Runner, mpinger_session::MPingerSession,
+    mpinger_tcp_connect::MPingerTCPConnect, mpinger_udp::MPingerUDP, utils,
 };
 use anyhow::Result;
 use socket2::SockAddr;
-use std::net::IpAddr;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
 use std::time::Duration;
+use time::OffsetDateTime;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MPingerMessageKind {
+    // ping result
+    Ping,
+    // destination was added to a running session
+    DestinationStarted,
+    // destination was removed, its session stopped or all its pings are done
+    DestinationStopped,
+    // the hostname of the destination was re-resolved and its address is no longer valid
+    AddressChanged { old: IpAddr, new: IpAddr },
+    // the hostname couldn't be resolved again, the destination keeps pinging its last address,
+    // the result of the message is MPingerResult::DnsFailure
+    ResolveFailed,
+}
+
+// Outcome of a single ping
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MPingerResult {
+    Success,
+    Timeout,
+    ConnectionRefused,
+    // ICMP Destination Unreachable, with the address of the host which reported it if known
+    Unreachable(Option<IpAddr>),
+    // ICMP Time Exceeded, with the address of the router which reported it if known
+    TtlExceeded(Option<IpAddr>),
+    // the hostname couldn't be resolved, by a new HTTP connection or again after
+    // MPingerConfig::dns_ttl, see MPingerMessageKind::ResolveFailed
+    DnsFailure,
+    // DNS response with a non-zero rcode, e.g. 3 for NXDOMAIN
+    DnsRcode(u8),
+    // HTTP response with a status code other than 2xx or 3xx,
+    // or other than MPingerHttpRequest::expected_status if set
+    HttpStatus(u16),
+    // HTTP response body not matching MPingerHttpRequest::expected_body
+    HttpBodyMismatch,
+    // TLS handshake failed
+    TlsError,
+    // the certificate chain of the server couldn't be verified
+    TlsCertificate(MPingerTlsChainStatus),
+    // the server certificate expires within MPingerTlsOptions::expiry_warning_days,
+    // with the days left
+    TlsCertExpiring(i64),
+    // UDP reply that isn't an echo of the probe
+    UdpPayloadMismatch,
+    SendError,
+    // any other failure, e.g. the connection was closed
+    Error,
+}
+
+impl MPingerResult {
+    pub fn is_success(&self) -> bool {
+        *self == MPingerResult::Success
+    }
+
+    // classify a failed socket operation
+    pub fn from_io_error(e: &std::io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => MPingerResult::Timeout,
+            ErrorKind::ConnectionRefused => MPingerResult::ConnectionRefused,
+            ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => {
+                MPingerResult::Unreachable(None)
+            }
+            _ => MPingerResult::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for MPingerResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MPingerResult::Success => write!(f, "success"),
+            MPingerResult::Timeout => write!(f, "timeout"),
+            MPingerResult::ConnectionRefused => write!(f, "connection refused"),
+            MPingerResult::Unreachable(Some(from)) => write!(f, "unreachable (from {})", from),
+            MPingerResult::Unreachable(None) => write!(f, "unreachable"),
+            MPingerResult::TtlExceeded(Some(from)) => write!(f, "TTL exceeded (from {})", from),
+            MPingerResult::TtlExceeded(None) => write!(f, "TTL exceeded"),
+            MPingerResult::DnsFailure => write!(f, "DNS failure"),
+            MPingerResult::DnsRcode(rcode) => match rcode {
+                1 => write!(f, "DNS FORMERR"),
+                2 => write!(f, "DNS SERVFAIL"),
+                3 => write!(f, "DNS NXDOMAIN"),
+                4 => write!(f, "DNS NOTIMP"),
+                5 => write!(f, "DNS REFUSED"),
+                _ => write!(f, "DNS rcode {}", rcode),
+            },
+            MPingerResult::HttpStatus(status) => write!(f, "HTTP status {}", status),
+            MPingerResult::HttpBodyMismatch => write!(f, "HTTP body mismatch"),
+            MPingerResult::TlsError => write!(f, "TLS error"),
+            MPingerResult::TlsCertificate(status) => write!(f, "TLS certificate {}", status),
+            MPingerResult::TlsCertExpiring(days) => {
+                write!(f, "TLS certificate expires in {} days", days)
+            }
+            MPingerResult::UdpPayloadMismatch => write!(f, "UDP payload mismatch"),
+            MPingerResult::SendError => write!(f, "send error"),
+            MPingerResult::Error => write!(f, "error"),
+        }
+    }
+}
+
+// Runner specific details of a ping result
+#[derive(Debug, Default, PartialEq, Clone)]
+pub enum MPingerDetails {
+    #[default]
+    None,
+    Http(MPingerHttpDetails),
+    Tls(MPingerTlsDetails),
+    Udp(MPingerUdpDetails),
+}
+
+// Sequence of MPingerType::UDPPing probes, the replies to earlier probes are counted
+// on the probe they arrived during
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct MPingerUdpDetails {
+    pub seq: u64,
+    pub payload_size: usize,
+    // replies to probes which already timed out
+    pub late_replies: usize,
+    // replies received more than once
+    pub duplicate_replies: usize,
+    // late replies arriving after the reply to a later probe
+    pub reordered_replies: usize,
+    // one-way delays to and from a reflector stamping the probes, only meaningful if the clocks
+    // of both hosts are synchronized, and the time the reflector held the probe
+    pub forward_delay: Option<time::Duration>,
+    pub reverse_delay: Option<time::Duration>,
+    pub reflector_time: Option<Duration>,
+}
+
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MPingerHttpDetails {
+    pub status: u16,
+    // body size, after removing the chunked encoding
+    pub body_size: usize,
+    // the server closes the connection after this response
+    pub connection_close: bool,
+    // status line, headers and body as received
+    pub bytes_received: usize,
+    // phases of the ping, from its start like curl's -w times:
+    // name lookup and TCP connect, only when the ping opened a new connection,
+    // there is no lookup for IP addresses and the other addresses of a hostname
+    pub dns_time: Option<Duration>,
+    pub connect_time: Option<Duration>,
+    // request written, first byte and last byte of the response received
+    pub request_time: Duration,
+    pub first_byte_time: Duration,
+    pub total_time: Duration,
+    // TLS handshake done, only when the ping opened a new HTTPS connection
+    pub tls_time: Option<Duration>,
+    // connections opened again so far, after a failure or the server closing the previous one,
+    // always 0 with MPingerHttpRequest::new_connection
+    pub reconnects: usize,
+    // session of the HTTPS connection the request was sent on
+    pub tls: Option<MPingerTlsDetails>,
+}
+
+// TLS session of MPingerType::TLSHandshake and MPingerType::HTTPSKeepAlive destinations
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct MPingerTlsDetails {
+    // e.g. TLSv1.3 and TLS13_AES_256_GCM_SHA384
+    pub version: String,
+    pub cipher: String,
+    // how long the TCP connect and the TLS handshake took
+    pub connect_time: Duration,
+    pub handshake_time: Duration,
+    // expiry of the server certificate and the days left until then, negative once expired
+    pub cert_not_after: Option<OffsetDateTime>,
+    pub cert_expires_in_days: Option<i64>,
+    // verification of the certificate chain against the root certificates,
+    // even with MPingerTlsOptions::skip_verify
+    pub chain_status: MPingerTlsChainStatus,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Copy, Clone)]
+pub enum MPingerTlsChainStatus {
+    // no certificate was received
+    #[default]
+    Unknown,
+    Valid,
+    Expired,
+    NotValidYet,
+    // not signed by any of the root certificates, e.g. self-signed
+    UnknownIssuer,
+    NotValidForName,
+    Revoked,
+    // any other failure
+    Invalid,
+}
+
+impl std::fmt::Display for MPingerTlsChainStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MPingerTlsChainStatus::Unknown => write!(f, "unknown"),
+            MPingerTlsChainStatus::Valid => write!(f, "valid"),
+            MPingerTlsChainStatus::Expired => write!(f, "expired"),
+            MPingerTlsChainStatus::NotValidYet => write!(f, "not valid yet"),
+            MPingerTlsChainStatus::UnknownIssuer => write!(f, "unknown issuer"),
+            MPingerTlsChainStatus::NotValidForName => write!(f, "not valid for name"),
+            MPingerTlsChainStatus::Revoked => write!(f, "revoked"),
+            MPingerTlsChainStatus::Invalid => write!(f, "invalid"),
+        }
+    }
+}
+
+// TLS options of MPingerType::TLSHandshake and MPingerType::HTTPSKeepAlive destinations
+#[derive(Debug, Default, Clone)]
+pub struct MPingerTlsOptions {
+    // name sent as SNI and verified by the certificate, the destination host if not set
+    pub server_name: Option<String>,
+    // accept any certificate, e.g. self-signed ones
+    pub skip_verify: bool,
+    // DER encoded certificates trusted besides the webpki roots, e.g. of a private CA
+    pub ca_certificates: Vec<Vec<u8>>,
+    // pings fail when the server certificate expires within this many days
+    pub expiry_warning_days: Option<u32>,
+}
+
+impl MPingerTlsOptions {
+    // result of a successful handshake, failing if the certificate is invalid or expires soon
+    pub fn check(&self, tls: &MPingerTlsDetails) -> MPingerResult {
+        if !self.skip_verify && tls.chain_status != MPingerTlsChainStatus::Valid {
+            return MPingerResult::TlsCertificate(tls.chain_status);
+        }
+
+        match (self.expiry_warning_days, tls.cert_expires_in_days) {
+            (Some(warning_days), Some(days)) if days < warning_days as i64 => {
+                MPingerResult::TlsCertExpiring(days)
+            }
+            _ => MPingerResult::Success,
+        }
+    }
+}
+
+// Request sent by MPingerType::HTTPKeepAlive destinations and the responses accepted as successful
+#[derive(Debug, Clone)]
+pub struct MPingerHttpRequest {
+    pub method: String,
+    pub path: String,
+    // extra headers, a Host header replaces the one built from the destination host
+    pub headers: Vec<(String, String)>,
+    // sent with a Content-Length header, e.g. for POST requests
+    pub body: Option<String>,
+    // the only successful status, instead of any 2xx or 3xx status
+    pub expected_status: Option<u16>,
+    pub expected_body: Option<MPingerHttpBodyMatch>,
+    // open a new connection for every ping instead of keeping it alive
+    pub new_connection: bool,
+}
+
+impl Default for MPingerHttpRequest {
+    fn default() -> Self {
+        MPingerHttpRequest {
+            method: "GET".to_string(),
+            path: "/".to_string(),
+            headers: Vec::new(),
+            body: None,
+            expected_status: None,
+            expected_body: None,
+            new_connection: false,
+        }
+    }
+}
+
+impl MPingerHttpRequest {
+    // responses to HEAD requests have no body, whatever their headers say
+    pub fn is_head(&self) -> bool {
+        self.method.eq_ignore_ascii_case("HEAD")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum MPingerHttpBodyMatch {
+    Contains(String),
+    Regex(regex::Regex),
+}
+
+impl MPingerHttpBodyMatch {
+    pub fn regex(pattern: &str) -> Result<Self> {
+        Ok(MPingerHttpBodyMatch::Regex(regex::Regex::new(pattern)?))
+    }
+
+    pub fn matches(&self, body: &str) -> bool {
+        match self {
+            MPingerHttpBodyMatch::Contains(text) => body.contains(text.as_str()),
+            MPingerHttpBodyMatch::Regex(regex) => regex.is_match(body),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct MPingerMessage {
+    pub kind: MPingerMessageKind,
     pub destination_id: usize,
     pub ping_nr: usize,
     pub runner_type: MPingerType,
-    pub start_timestamp: i64,
-    pub duration: u64,
-    pub is_error: bool,
+    // wall-clock time taken right before the ping was sent
+    pub send_time: OffsetDateTime,
+    // round trip time, zero when no response was received
+    pub rtt: Duration,
+    pub result: MPingerResult,
+    pub details: MPingerDetails,
+}
+
+impl MPingerMessage {
+    // ping result
+    pub fn new_ping(
+        dest: &MPingDestination,
+        ping_nr: usize,
+        send_time: OffsetDateTime,
+        rtt: Duration,
+        result: MPingerResult,
+    ) -> Self {
+        MPingerMessage {
+            kind: MPingerMessageKind::Ping,
+            destination_id: dest.id,
+            ping_nr,
+            runner_type: dest.ping_type,
+            send_time,
+            rtt,
+            result,
+            details: MPingerDetails::None,
+        }
+    }
+
+    pub fn with_details(mut self, details: MPingerDetails) -> Self {
+        self.details = details;
+        self
+    }
+
+    // failed ping, sent before any probe could be made
+    pub fn new_error(dest: &MPingDestination, ping_nr: usize, result: MPingerResult) -> Self {
+        MPingerMessage::new_ping(
+            dest,
+            ping_nr,
+            OffsetDateTime::now_utc(),
+            Duration::ZERO,
+            result,
+        )
+    }
+
+    pub fn is_error(&self) -> bool {
+        !self.result.is_success()
+    }
+
+    // destination lifecycle message
+    pub fn new_event(dest: &MPingDestination, kind: MPingerMessageKind) -> Self {
+        MPingerMessage {
+            kind,
+            destination_id: dest.id,
+            ping_nr: 0,
+            runner_type: dest.ping_type,
+            send_time: OffsetDateTime::now_utc(),
+            rtt: Duration::ZERO,
+            result: MPingerResult::Success,
+            details: MPingerDetails::None,
+        }
+    }
 }
 impl Iterator for MPingerReader {
     type Item = MPingerMessage;
 
+    // ends once the session is over, however long the pings take
     fn next(&mut self) -> Option<Self::Item> {
-        let timeout = std::time::Duration::from_millis(self.config.read().unwrap().next_timeout);
-        self.recv_timeout(timeout).ok()
+        self.recv().ok()
+    }
+}
+
+// Stops a running session, see MPingerReader::get_handle
+#[derive(Clone)]
+pub struct MPingerHandle {
+    session: Arc<MPingerSession>,
+}
+
+impl MPingerHandle {
+    pub(crate) fn new(session: Arc<MPingerSession>) -> Self {
+        Self { session }
+    }
+
+    // runners finish their current ping and the reader ends once all of them are done
+    pub fn stop(&self) {
+        self.session.stop();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.session.is_stopped()
+    }
+}
+
+impl std::fmt::Debug for MPingerHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MPingerHandle")
+            .field("stopped", &self.is_stopped())
+            .finish()
     }
 }
 
 #[derive(Debug)]
 pub struct MPingerReader {
-    config: MPingerConfigShared,
     rx: Arc<Mutex<Receiver<MPingerMessage>>>,
+    handle: MPingerHandle,
 }
 
 impl MPingerReader {
-    pub fn new(config: MPingerConfigShared, rx: Arc<Mutex<Receiver<MPingerMessage>>>) -> Self {
-        Self { config, rx }
+    pub fn new(rx: Arc<Mutex<Receiver<MPingerMessage>>>, handle: MPingerHandle) -> Self {
+        Self { rx, handle }
+    }
+
+    pub fn get_handle(&self) -> MPingerHandle {
+        self.handle.clone()
     }
 
     pub fn get_rx(&self) -> Arc<Mutex<Receiver<MPingerMessage>>> {
@@ -66,14 +455,153 @@ pub struct MPingDestination {
     pub id: usize,
     // original address
     pub address: String,
-    // host part
+    // host part, a hostname or an IP literal
     pub host: String,
     // port part
     pub port: u16,
-    // resolved ip
-    pub sock_addr: SockAddr,
+    // address family used to resolve the host
+    pub ip_family: MPingerIpFamily,
     // type
     pub ping_type: MPingerType,
+    // destination whose hostname resolved to this address, see MPingerConfig::resolve_all
+    pub parent_id: Option<usize>,
+    // request of HTTP destinations, GET / when not set
+    pub http_request: Option<Arc<MPingerHttpRequest>>,
+    // options of TLS destinations, the defaults when not set
+    pub tls: Option<Arc<MPingerTlsOptions>>,
+    // name given by the user, e.g. in a config file
+    pub name: Option<String>,
+    pub tags: Vec<String>,
+    // settings of the destination replacing the global ones, shared by all the clones
+    overrides: Arc<RwLock<MPingerConfigOverrides>>,
+    // resolved address, shared by all the clones so runners see re-resolutions
+    socket_addr: Arc<RwLock<SocketAddr>>,
+}
+
+impl MPingDestination {
+    pub(crate) fn new(
+        id: usize,
+        address: &str,
+        host: &str,
+        socket_addr: SocketAddr,
+        ip_family: MPingerIpFamily,
+        ping_type: MPingerType,
+    ) -> Self {
+        Self {
+            id,
+            address: address.to_string(),
+            host: host.to_string(),
+            port: socket_addr.port(),
+            ip_family,
+            ping_type,
+            parent_id: None,
+            http_request: None,
+            tls: None,
+            name: None,
+            tags: Vec::new(),
+            overrides: Arc::new(RwLock::new(MPingerConfigOverrides::default())),
+            socket_addr: Arc::new(RwLock::new(socket_addr)),
+        }
+    }
+
+    // another address of the same hostname, pinged as a separate destination
+    pub(crate) fn new_child(&self, id: usize, ip: IpAddr) -> Self {
+        let mut child = MPingDestination::new(
+            id,
+            &self.address,
+            &self.host,
+            SocketAddr::new(ip, self.port),
+            self.ip_family,
+            self.ping_type,
+        );
+        child.parent_id = Some(self.id);
+        child.http_request = self.http_request.clone();
+        child.tls = self.tls.clone();
+        child.name = self.name.clone();
+        child.tags = self.tags.clone();
+        child.overrides = self.overrides.clone();
+
+        child
+    }
+
+    // current address, runners should read it before every ping
+    pub fn socket_addr(&self) -> SocketAddr {
+        *self.socket_addr.read().unwrap()
+    }
+
+    pub fn sock_addr(&self) -> SockAddr {
+        SockAddr::from(self.socket_addr())
+    }
+
+    pub fn ip(&self) -> IpAddr {
+        self.socket_addr().ip()
+    }
+
+    // returns the previous address
+    pub(crate) fn set_ip(&self, ip: IpAddr) -> IpAddr {
+        let mut socket_addr = self.socket_addr.write().unwrap();
+        let old = socket_addr.ip();
+        socket_addr.set_ip(ip);
+
+        old
+    }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.socket_addr().is_ipv6()
+    }
+
+    // true if the address needs to be resolved, false for IP literals
+    pub fn is_hostname(&self) -> bool {
+        self.host.parse::<IpAddr>().is_err()
+    }
+
+    pub fn overrides(&self) -> MPingerConfigOverrides {
+        self.overrides.read().unwrap().clone()
+    }
+
+    pub(crate) fn set_overrides(&self, overrides: MPingerConfigOverrides) {
+        *self.overrides.write().unwrap() = overrides;
+    }
+
+    // runners should read the settings before every ping, they can be changed
+    pub fn ping_interval(&self, config: &MPingerConfigShared) -> Duration {
+        let ping_interval = self.overrides.read().unwrap().ping_interval;
+        let ping_interval = ping_interval.unwrap_or_else(|| config.read().unwrap().ping_interval);
+        Duration::from_millis(ping_interval)
+    }
+
+    pub fn timeout(&self, config: &MPingerConfigShared) -> Duration {
+        let timeout = self.overrides.read().unwrap().timeout;
+        let timeout = timeout.unwrap_or_else(|| config.read().unwrap().timeout);
+        Duration::from_millis(timeout)
+    }
+
+    pub fn ping_retries(&self, config: &MPingerConfigShared) -> usize {
+        let ping_retries = self.overrides.read().unwrap().ping_retries;
+        ping_retries.unwrap_or_else(|| config.read().unwrap().ping_retries)
+    }
+
+    pub fn payload_size(&self) -> Option<usize> {
+        self.overrides.read().unwrap().payload_size
+    }
+
+    pub fn dns_query_name(&self, config: &MPingerConfigShared) -> String {
+        let dns_query_name = self.overrides.read().unwrap().dns_query_name.clone();
+        dns_query_name.unwrap_or_else(|| config.read().unwrap().dns_query_name.clone())
+    }
+
+    pub fn dns_query_type(&self, config: &MPingerConfigShared) -> MPingerDNSRecordType {
+        let dns_query_type = self.overrides.read().unwrap().dns_query_type;
+        dns_query_type.unwrap_or_else(|| config.read().unwrap().dns_query_type)
+    }
+
+    // result of a completed TLS handshake, with the default options if none were given
+    pub(crate) fn check_tls(&self, tls: &MPingerTlsDetails) -> MPingerResult {
+        match self.tls.as_ref() {
+            Some(options) => options.check(tls),
+            None => MPingerTlsOptions::default().check(tls),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
@@ -82,7 +610,92 @@ pub enum MPingerType {
     TCPConnect,
     HTTPKeepAlive,
     UDPPing,
+    DNSQuery,
     Rnd,
+    // need the tls feature
+    TLSHandshake,
+    HTTPSKeepAlive,
+    // user-defined runner, see MPinger::add_destination_with_runner
+    Custom(usize),
+}
+
+pub type MPingerRunners = HashMap<MPingerType, Arc<dyn Runner>>;
+
+#[derive(Debug, Default, PartialEq, Eq, Hash, Copy, Clone)]
+#[cfg_attr(
+    feature = "config",
+    derive(serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum MPingerIpFamily {
+    // use the first resolved address, IPv4 or IPv6
+    #[default]
+    Any,
+    // only IPv4 addresses
+    V4,
+    // only IPv6 addresses
+    V6,
+}
+
+impl MPingerIpFamily {
+    pub fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            MPingerIpFamily::Any => true,
+            MPingerIpFamily::V4 => ip.is_ipv4(),
+            MPingerIpFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+// Record type queried by MPingerType::DNSQuery
+#[derive(Debug, Default, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum MPingerDNSRecordType {
+    #[default]
+    A,
+    NS,
+    CNAME,
+    SOA,
+    PTR,
+    MX,
+    TXT,
+    AAAA,
+    SRV,
+}
+
+impl MPingerDNSRecordType {
+    // QTYPE value sent in the query
+    pub fn code(&self) -> u16 {
+        match self {
+            MPingerDNSRecordType::A => 1,
+            MPingerDNSRecordType::NS => 2,
+            MPingerDNSRecordType::CNAME => 5,
+            MPingerDNSRecordType::SOA => 6,
+            MPingerDNSRecordType::PTR => 12,
+            MPingerDNSRecordType::MX => 15,
+            MPingerDNSRecordType::TXT => 16,
+            MPingerDNSRecordType::AAAA => 28,
+            MPingerDNSRecordType::SRV => 33,
+        }
+    }
+}
+
+impl std::str::FromStr for MPingerDNSRecordType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(MPingerDNSRecordType::A),
+            "NS" => Ok(MPingerDNSRecordType::NS),
+            "CNAME" => Ok(MPingerDNSRecordType::CNAME),
+            "SOA" => Ok(MPingerDNSRecordType::SOA),
+            "PTR" => Ok(MPingerDNSRecordType::PTR),
+            "MX" => Ok(MPingerDNSRecordType::MX),
+            "TXT" => Ok(MPingerDNSRecordType::TXT),
+            "AAAA" => Ok(MPingerDNSRecordType::AAAA),
+            "SRV" => Ok(MPingerDNSRecordType::SRV),
+            _ => Err(format!("Unknown DNS record type: {}", s)),
+        }
+    }
 }
 
 pub type MPingerConfigShared = Arc<RwLock<MPingerConfig>>;
@@ -91,92 +704,352 @@ pub type MPingerConfigShared = Arc<RwLock<MPingerConfig>>;
 pub struct MPingerConfig {
     pub ping_interval: u64, // ms
     pub timeout: u64,       // ms
-    pub next_timeout: u64,  // ms
+    #[deprecated(note = "readers wait for the next message until the session is over")]
+    pub next_timeout: u64, // ms
+    // failed pings are sent again this many times before they are reported
     pub ping_retries: usize,
     pub default_port: u16,
+    pub ip_family: MPingerIpFamily,
+    // hostnames are resolved again after dns_ttl ms, 0 to resolve them only once
+    pub dns_ttl: u64,
+    // ping every address a hostname resolves to as a separate destination
+    pub resolve_all: bool,
+    // name and record type queried by MPingerType::DNSQuery destinations, which are resolvers
+    pub dns_query_name: String,
+    pub dns_query_type: MPingerDNSRecordType,
 }
+#[allow(deprecated)]
 impl Default for MPingerConfig {
     fn default() -> Self {
         MPingerConfig {
             ping_interval: 1000,
             timeout: 1000,
             next_timeout: 5000,
-            ping_retries: 3,
+            ping_retries: 0,
             default_port: 80,
+            ip_family: MPingerIpFamily::Any,
+            dns_ttl: 60_000,
+            resolve_all: false,
+            dns_query_name: "example.com".to_string(),
+            dns_query_type: MPingerDNSRecordType::A,
         }
     }
 }
 
+// Settings of a destination used instead of the ones of MPingerConfig, when set
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MPingerConfigOverrides {
+    pub ping_interval: Option<u64>, // ms
+    pub timeout: Option<u64>,       // ms
+    pub ping_retries: Option<usize>,
+    // bytes of ICMP Echo data, or UDP probe padded with zeros, which is at least 40 bytes
+    pub payload_size: Option<usize>,
+    // query sent by DNS query destinations
+    pub dns_query_name: Option<String>,
+    pub dns_query_type: Option<MPingerDNSRecordType>,
+}
+
+// Settings of a new destination besides its address
+#[derive(Default)]
+struct MPingerDestinationOptions {
+    http_request: Option<Arc<MPingerHttpRequest>>,
+    tls: Option<Arc<MPingerTlsOptions>>,
+    // used when the address has no port, instead of the default one of the runner
+    port: Option<u16>,
+    name: Option<String>,
+    tags: Vec<String>,
+    overrides: MPingerConfigOverrides,
+}
+
 #[derive(Debug)]
 pub struct MPinger {
     config: MPingerConfigShared,
-    // runners: HashMap<MPingerType, MPingerRunnerStruct>,
-    rx: Arc<Mutex<Receiver<MPingerMessage>>>,
-    tx: mpsc::Sender<MPingerMessage>,
-    total_addresses: usize,
-    destinations: Vec<MPingDestination>,
+    runners: MPingerRunners,
+    // destinations and running sessions, shared with the thread re-resolving hostnames
+    registry: Arc<Mutex<MPingerRegistry>>,
 }
 
 impl MPinger {
     pub fn new(config: MPingerConfig) -> Self {
-        let (tx, rx) = mpsc::channel();
-
         let config = Arc::new(RwLock::new(config));
 
-        let rx = Arc::new(Mutex::new(rx));
+        let mut runners: MPingerRunners = HashMap::new();
+        runners.insert(MPingerType::ICMPPing, Arc::new(MPingerICMP::new()));
+        runners.insert(MPingerType::TCPConnect, Arc::new(MPingerTCPConnect()));
+        runners.insert(MPingerType::HTTPKeepAlive, Arc::new(MPingerHTTPKeepAlive()));
+        runners.insert(MPingerType::UDPPing, Arc::new(MPingerUDP()));
+        runners.insert(MPingerType::DNSQuery, Arc::new(MPingerDNSQuery()));
+        runners.insert(MPingerType::Rnd, Arc::new(MPingerRnd()));
+        #[cfg(feature = "tls")]
+        {
+            runners.insert(MPingerType::TLSHandshake, Arc::new(MPingerTLS()));
+            runners.insert(
+                MPingerType::HTTPSKeepAlive,
+                Arc::new(MPingerHTTPSKeepAlive()),
+            );
+        }
 
         Self {
             config,
-            rx,
-            tx,
-            total_addresses: 0,
-            destinations: Vec::new(),
+            runners,
+            registry: Arc::new(Mutex::new(MPingerRegistry::default())),
         }
     }
 
+    // settings and destinations of a TOML or YAML file, see MPingerConfigFile
+    #[cfg(feature = "config")]
+    pub fn from_config(path: impl AsRef<std::path::Path>) -> Result<Self> {
+        let file = MPingerConfigFile::load(path)?;
+
+        let mut config = MPingerConfig::default();
+        file.settings.apply(&mut config)?;
+
+        let mut pinger = MPinger::new(config);
+        for dest in file.destinations.iter() {
+            pinger.add_destination_config(dest)?;
+        }
+
+        Ok(pinger)
+    }
+
+    // destination of a config file, with its name, tags and overrides
+    #[cfg(feature = "config")]
+    pub fn add_destination_config(&mut self, dest: &MPingerDestinationConfig) -> Result<usize> {
+        let ip_family = self.config.read().unwrap().ip_family;
+        let overrides = dest
+            .overrides()
+            .map_err(|e| anyhow::anyhow!("Error adding {}: {}", dest.address, e))?;
+        self.add_destination_with_options(
+            dest.probe_type.into(),
+            &dest.address,
+            ip_family,
+            MPingerDestinationOptions {
+                http_request: dest.http_request.clone().map(Arc::new),
+                tls: dest.tls.clone().map(Arc::new),
+                port: dest.port,
+                name: dest.name.clone(),
+                tags: dest.tags.clone(),
+                overrides,
+            },
+        )
+        .map_err(|e| anyhow::anyhow!("Error adding {}: {}", dest.address, e))
+    }
+
     //try to parse and resolve, add to the appropiate runner
     pub fn add_destination(&mut self, runner_type: MPingerType, addr: &str) -> Result<usize> {
-        let default_port = match runner_type {
-            MPingerType::UDPPing => 8888,
-            _ => self.config.read().unwrap().default_port,
+        let ip_family = self.config.read().unwrap().ip_family;
+        self.add_destination_with_family(runner_type, addr, ip_family)
+    }
+
+    // same as add_destination, but resolve the host to the given address family
+    pub fn add_destination_with_family(
+        &mut self,
+        runner_type: MPingerType,
+        addr: &str,
+        ip_family: MPingerIpFamily,
+    ) -> Result<usize> {
+        self.add_destination_with_options(
+            runner_type,
+            addr,
+            ip_family,
+            MPingerDestinationOptions::default(),
+        )
+    }
+
+    // destination with its own interval, timeout, retries, payload size or DNS query
+    // instead of the global ones
+    pub fn add_destination_with_overrides(
+        &mut self,
+        runner_type: MPingerType,
+        addr: &str,
+        overrides: MPingerConfigOverrides,
+    ) -> Result<usize> {
+        let ip_family = self.config.read().unwrap().ip_family;
+        self.add_destination_with_options(
+            runner_type,
+            addr,
+            ip_family,
+            MPingerDestinationOptions {
+                overrides,
+                ..Default::default()
+            },
+        )
+    }
+
+    // applies from the next ping of the destination and of the other addresses of its hostname,
+    // returns false if there is no such destination
+    pub fn set_destination_overrides(
+        &mut self,
+        id: usize,
+        overrides: MPingerConfigOverrides,
+    ) -> bool {
+        match self.registry.lock().unwrap().get(id) {
+            Some(dest) => {
+                dest.set_overrides(overrides);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // HTTP keepalive destination sending the given request instead of GET /
+    pub fn add_http_destination(
+        &mut self,
+        addr: &str,
+        http_request: MPingerHttpRequest,
+    ) -> Result<usize> {
+        let ip_family = self.config.read().unwrap().ip_family;
+        self.add_destination_with_options(
+            MPingerType::HTTPKeepAlive,
+            addr,
+            ip_family,
+            MPingerDestinationOptions {
+                http_request: Some(Arc::new(http_request)),
+                ..Default::default()
+            },
+        )
+    }
+
+    // TLS handshake or HTTPS keepalive destination with the given TLS options
+    pub fn add_tls_destination(
+        &mut self,
+        runner_type: MPingerType,
+        addr: &str,
+        tls: MPingerTlsOptions,
+    ) -> Result<usize> {
+        let ip_family = self.config.read().unwrap().ip_family;
+        self.add_destination_with_options(
+            runner_type,
+            addr,
+            ip_family,
+            MPingerDestinationOptions {
+                tls: Some(Arc::new(tls)),
+                ..Default::default()
+            },
+        )
+    }
+
+    // HTTPS keepalive destination sending the given request with the given TLS options
+    pub fn add_https_destination(
+        &mut self,
+        addr: &str,
+        http_request: MPingerHttpRequest,
+        tls: MPingerTlsOptions,
+    ) -> Result<usize> {
+        let ip_family = self.config.read().unwrap().ip_family;
+        self.add_destination_with_options(
+            MPingerType::HTTPSKeepAlive,
+            addr,
+            ip_family,
+            MPingerDestinationOptions {
+                http_request: Some(Arc::new(http_request)),
+                tls: Some(Arc::new(tls)),
+                ..Default::default()
+            },
+        )
+    }
+
+    fn add_destination_with_options(
+        &mut self,
+        runner_type: MPingerType,
+        addr: &str,
+        ip_family: MPingerIpFamily,
+        options: MPingerDestinationOptions,
+    ) -> Result<usize> {
+        let runner = match self.runners.get(&runner_type) {
+            Some(runner) => runner.clone(),
+            None => return Err(anyhow::anyhow!("Unknown runner type: {:?}", runner_type)),
         };
 
-        let (ip, port) = match utils::parse_host_port(addr, default_port) {
-            Ok((ip, port)) => (ip, port),
+        let (default_port, resolve_all) = {
+            let config = self.config.read().unwrap();
+            (
+                options
+                    .port
+                    .or(runner.default_port())
+                    .unwrap_or(config.default_port),
+                config.resolve_all,
+            )
+        };
+
+        let (host, ips, port) = match utils::parse_host_port(addr, default_port, ip_family) {
+            Ok((host, ips, port)) => (host, ips, port),
             Err(e) => {
                 return Err(anyhow::anyhow!(e));
             }
         };
 
-        let sock_addr = SockAddr::from(std::net::SocketAddr::new(IpAddr::V4(ip), port));
+        let mut registry = self.registry.lock().unwrap();
 
-        self.total_addresses += 1;
+        let mut dest = MPingDestination::new(
+            registry.next_id(),
+            addr,
+            &host,
+            SocketAddr::new(ips[0], port),
+            ip_family,
+            runner_type,
+        );
+        dest.http_request = options.http_request;
+        dest.tls = options.tls;
+        dest.name = options.name;
+        dest.tags = options.tags;
+        dest.set_overrides(options.overrides);
+        let id = dest.id;
 
-        let dest = MPingDestination {
-            id: self.total_addresses,
-            ping_type: runner_type,
-            address: addr.to_string(),
-            host: ip.to_string(),
-            port,
-            sock_addr,
-        };
+        // start pinging right away if already running
+        registry.add(dest.clone(), runner.clone());
 
-        self.destinations.push(dest.clone());
+        if resolve_all && dest.is_hostname() {
+            for ip in &ips[1..] {
+                let child = dest.new_child(registry.next_id(), *ip);
+                registry.add(child, runner.clone());
+            }
+        }
+
+        Ok(id)
+    }
 
-        Ok(self.total_addresses)
+    // stop pinging the destination and the other addresses of its hostname,
+    // running sessions send a DestinationStopped message
+    pub fn remove_destination(&mut self, id: usize) -> bool {
+        self.registry.lock().unwrap().remove(id)
     }
 
-    pub fn get_destination_by_id(&self, id: usize) -> Option<&MPingDestination> {
-        self.destinations.iter().find(|&dest| dest.id == id)
+    // add a destination pinged by a user-defined runner
+    pub fn add_destination_with_runner(
+        &mut self,
+        runner: Arc<dyn Runner>,
+        addr: &str,
+    ) -> Result<usize> {
+        let runner_type = self.register_runner(runner);
+        self.add_destination(runner_type, addr)
+    }
+
+    // returns the type assigned to the runner, registering it on first use
+    pub fn register_runner(&mut self, runner: Arc<dyn Runner>) -> MPingerType {
+        if let Some((runner_type, _)) = self
+            .runners
+            .iter()
+            .find(|(_, registered)| Arc::ptr_eq(registered, &runner))
+        {
+            return *runner_type;
+        }
+
+        let runner_type = MPingerType::Custom(self.runners.len());
+        self.runners.insert(runner_type, runner);
+
+        runner_type
+    }
+
+    // destinations added for the other addresses of a hostname are included
+    pub fn get_destination_by_id(&self, id: usize) -> Option<MPingDestination> {
+        self.registry.lock().unwrap().get(id)
     }
 
     pub fn get_runner_description(&self, runner_type: &MPingerType) -> &str {
-        match runner_type {
-            MPingerType::ICMPPing => "ICMP ping",
-            MPingerType::TCPConnect => "TCP Connect",
-            MPingerType::HTTPKeepAlive => "HTTP Keep Alive",
-            MPingerType::UDPPing => "UDP ping",
-            MPingerType::Rnd => "Random",
+        match self.runners.get(runner_type) {
+            Some(runner) => runner.name(),
+            None => "Unknown",
         }
     }
 
@@ -191,57 +1064,29 @@ impl MPinger {
         self.config.read().unwrap().ping_interval
     }
 
-    fn ping_runner(
-        config: MPingerConfigShared,
-        destinations: Vec<MPingDestination>,
-        tx: mpsc::Sender<MPingerMessage>,
-        count: usize,
-    ) {
-        // run Connect pings
-        let handles: Vec<_> = destinations
-            .iter()
-            .map(|dest| {
-                let tx = tx.clone();
-                let config = config.clone();
-                let dest = dest.clone();
-
-                match dest.ping_type {
-                    MPingerType::ICMPPing => thread::spawn(move || {
-                        MPingerICMP::start(config.clone(), &dest, tx, count);
-                    }),
-                    MPingerType::TCPConnect => thread::spawn(move || {
-                        MPingerTCPConnect::start(config.clone(), &dest, tx, count);
-                    }),
-                    MPingerType::HTTPKeepAlive => thread::spawn(move || {
-                        MPingerHTTPKeepAlive::start(config.clone(), &dest, tx, count);
-                    }),
-                    MPingerType::UDPPing => thread::spawn(move || {
-                        MPingerUDP::start(config.clone(), &dest, tx, count);
-                    }),
-                    MPingerType::Rnd => thread::spawn(move || {
-                        MPingerRnd::start(config.clone(), &dest, tx, count);
-                    }),
-                }
-            })
-            .collect();
-
-        // Wait for all threads to complete
-        for handle in handles {
-            handle.join().unwrap();
-        }
-    }
-
-    pub fn start(&self, count: usize) -> MPingerReader {
-        if !self.destinations.is_empty() {
-            let destinations: Vec<MPingDestination> = self.destinations.clone();
-            let tx = self.tx.clone();
-            let config = self.config.clone();
-
-            thread::spawn(move || {
-                MPinger::ping_runner(config, destinations, tx, count);
-            });
-        }
-
-        MPingerReader::new(self.config.clone(), self.rx.clone())
+    fn start_session(&mut self, session: Arc<MPingerSession>) {
+        MPingerRegistry::start_session(&self.registry, &self.config, session);
+    }
+
+    // every session gets its own channel, the reader ends once the session is over:
+    // it was stopped, or all its pings are done when count is not 0
+    pub fn start(&mut self, count: usize) -> MPingerReader {
+        let (tx, rx) = mpsc::channel();
+        let session = MPingerSession::new_threads(self.config.clone(), count, tx);
+
+        self.start_session(session.clone());
+
+        MPingerReader::new(Arc::new(Mutex::new(rx)), MPingerHandle::new(session))
+    }
+
+    // same as start, but runs the pings as tasks on the current tokio runtime
+    #[cfg(feature = "tokio")]
+    pub fn start_async(&mut self, count: usize) -> crate::mpinger_async::MPingerStream {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let session = MPingerSession::new_tokio(self.config.clone(), count, tx);
+
+        self.start_session(session.clone());
+
+        crate::mpinger_async::MPingerStream::new(rx, MPingerHandle::new(session))
     }
 }
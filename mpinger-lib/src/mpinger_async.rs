@@ -0,0 +1,81 @@
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerHandle, MPingerMessage};
+use crate::mpinger_runner::{MPingerStopSignal, Runner};
+use futures_core::Stream;
+use log::error;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+
+// Stream of ping results returned by MPinger::start_async, ends when all runners are done
+#[derive(Debug)]
+pub struct MPingerStream {
+    rx: mpsc::UnboundedReceiver<MPingerMessage>,
+    handle: MPingerHandle,
+}
+
+impl MPingerStream {
+    pub fn new(rx: mpsc::UnboundedReceiver<MPingerMessage>, handle: MPingerHandle) -> Self {
+        Self { rx, handle }
+    }
+
+    pub fn get_handle(&self) -> MPingerHandle {
+        self.handle.clone()
+    }
+}
+
+impl Stream for MPingerStream {
+    type Item = MPingerMessage;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+// ping a single destination with the async version of its runner, or on the blocking
+// thread pool when it has none
+pub(crate) async fn run_destination(
+    runner: Arc<dyn Runner>,
+    config: MPingerConfigShared,
+    dest: &MPingDestination,
+    tx: mpsc::UnboundedSender<MPingerMessage>,
+    count: usize,
+    stop_signal: Arc<MPingerStopSignal>,
+) {
+    if let Some(ping) = runner.start_async(config.clone(), dest, tx.clone(), count, &stop_signal) {
+        ping.await;
+        return;
+    }
+
+    blocking_runner(runner, config, dest.clone(), tx, count, stop_signal).await;
+}
+
+// blocking runners run on the blocking thread pool and forward the results
+async fn blocking_runner(
+    runner: Arc<dyn Runner>,
+    config: MPingerConfigShared,
+    dest: MPingDestination,
+    tx: mpsc::UnboundedSender<MPingerMessage>,
+    count: usize,
+    stop_signal: Arc<MPingerStopSignal>,
+) {
+    let (runner_tx, runner_rx) = std::sync::mpsc::channel();
+
+    let forwarder = tokio::task::spawn_blocking(move || {
+        for message in runner_rx {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let result = tokio::task::spawn_blocking(move || {
+        runner.start(config, &dest, runner_tx, count, &stop_signal);
+    })
+    .await;
+    if let Err(e) = result {
+        error!("Runner task failed: {}", e);
+    }
+
+    let _ = forwarder.await;
+}
@@ -0,0 +1,435 @@
+use crate::mpinger::{
+    MPingerConfig, MPingerConfigOverrides, MPingerDNSRecordType, MPingerHttpRequest,
+    MPingerIpFamily, MPingerTlsOptions, MPingerType,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use std::path::Path;
+
+// Settings and destinations read from a TOML or YAML file, e.g.
+//
+//   [settings]
+//   interval = 1000
+//
+//   [[destinations]]
+//   name = "gateway"
+//   tags = ["lan"]
+//   type = "icmp"
+//   address = "192.168.1.1"
+//   interval = 200
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MPingerConfigFile {
+    #[serde(default)]
+    pub settings: MPingerSettings,
+    #[serde(default)]
+    pub destinations: Vec<MPingerDestinationConfig>,
+}
+
+impl MPingerConfigFile {
+    // the format is told by the extension, .toml, .yaml or .yml
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Error reading {}: {}", path.display(), e))?;
+
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let result = match extension.as_str() {
+            "toml" => MPingerConfigFile::from_toml(&content),
+            "yaml" | "yml" => MPingerConfigFile::from_yaml(&content),
+            _ => Err(anyhow::anyhow!(
+                "Unknown config file format, expected .toml or .yaml"
+            )),
+        };
+
+        result.map_err(|e| anyhow::anyhow!("Error loading {}: {}", path.display(), e))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Self> {
+        Ok(serde_yaml::from_str(content)?)
+    }
+}
+
+// Global settings, the ones of MPingerConfig are kept when not set
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MPingerSettings {
+    pub interval: Option<u64>, // ms
+    pub timeout: Option<u64>,  // ms
+    pub retries: Option<usize>,
+    // any, v4 or v6
+    pub ip_family: Option<MPingerIpFamily>,
+    pub dns_ttl: Option<u64>, // ms
+    pub resolve_all: Option<bool>,
+    pub dns_query_name: Option<String>,
+    // A, AAAA, MX, TXT, ...
+    pub dns_query_type: Option<String>,
+}
+
+impl MPingerSettings {
+    pub fn apply(&self, config: &mut MPingerConfig) -> Result<()> {
+        if let Some(interval) = self.interval {
+            config.ping_interval = interval;
+        }
+        if let Some(timeout) = self.timeout {
+            config.timeout = timeout;
+        }
+        if let Some(retries) = self.retries {
+            config.ping_retries = retries;
+        }
+        if let Some(ip_family) = self.ip_family {
+            config.ip_family = ip_family;
+        }
+        if let Some(dns_ttl) = self.dns_ttl {
+            config.dns_ttl = dns_ttl;
+        }
+        if let Some(resolve_all) = self.resolve_all {
+            config.resolve_all = resolve_all;
+        }
+        if let Some(dns_query_name) = self.dns_query_name.as_ref() {
+            config.dns_query_name = dns_query_name.clone();
+        }
+        if let Some(dns_query_type) = self.dns_query_type.as_ref() {
+            config.dns_query_type = dns_query_type
+                .parse::<MPingerDNSRecordType>()
+                .map_err(|e| anyhow::anyhow!(e))?;
+        }
+
+        Ok(())
+    }
+}
+
+// Destination of the file, added with MPinger::add_destination_config
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MPingerDestinationConfig {
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(rename = "type")]
+    pub probe_type: MPingerProbeType,
+    pub address: String,
+    // overrides of the global settings
+    pub interval: Option<u64>, // ms
+    pub timeout: Option<u64>,  // ms
+    pub retries: Option<usize>,
+    // used when the address has no port, instead of the default one of the probe type
+    pub port: Option<u16>,
+    // ICMP and UDP probes only
+    pub payload_size: Option<usize>,
+    // DNS probes only
+    pub dns_query_name: Option<String>,
+    pub dns_query_type: Option<String>,
+    // not read from the file, the application can set them, e.g. from its command line
+    #[serde(skip)]
+    pub http_request: Option<MPingerHttpRequest>,
+    #[serde(skip)]
+    pub tls: Option<MPingerTlsOptions>,
+}
+
+impl MPingerDestinationConfig {
+    pub fn overrides(&self) -> Result<MPingerConfigOverrides> {
+        let dns_query_type = match self.dns_query_type.as_ref() {
+            Some(dns_query_type) => Some(
+                dns_query_type
+                    .parse::<MPingerDNSRecordType>()
+                    .map_err(|e| anyhow::anyhow!(e))?,
+            ),
+            None => None,
+        };
+
+        Ok(MPingerConfigOverrides {
+            ping_interval: self.interval,
+            timeout: self.timeout,
+            ping_retries: self.retries,
+            payload_size: self.payload_size,
+            dns_query_name: self.dns_query_name.clone(),
+            dns_query_type,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MPingerProbeType {
+    ICMP,
+    Connect,
+    HTTP,
+    // need the tls feature
+    HTTPS,
+    TLS,
+    UDP,
+    DNS,
+}
+
+impl From<MPingerProbeType> for MPingerType {
+    fn from(probe_type: MPingerProbeType) -> Self {
+        match probe_type {
+            MPingerProbeType::ICMP => MPingerType::ICMPPing,
+            MPingerProbeType::Connect => MPingerType::TCPConnect,
+            MPingerProbeType::HTTP => MPingerType::HTTPKeepAlive,
+            MPingerProbeType::HTTPS => MPingerType::HTTPSKeepAlive,
+            MPingerProbeType::TLS => MPingerType::TLSHandshake,
+            MPingerProbeType::UDP => MPingerType::UDPPing,
+            MPingerProbeType::DNS => MPingerType::DNSQuery,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::{MPinger, MPingerConfigShared};
+    use std::sync::{Arc, RwLock};
+    use std::time::Duration;
+
+    const TOML: &str = r#"
+[settings]
+interval = 500
+timeout = 2000
+retries = 2
+ip_family = "v4"
+dns_query_type = "aaaa"
+
+[[destinations]]
+name = "gateway"
+tags = ["lan", "core"]
+type = "icmp"
+address = "192.168.1.1"
+interval = 200
+payload_size = 64
+
+[[destinations]]
+type = "dns"
+address = "127.0.0.1"
+timeout = 100
+dns_query_name = "example.com"
+dns_query_type = "MX"
+"#;
+
+    const YAML: &str = r#"
+settings:
+  interval: 500
+  timeout: 2000
+  retries: 2
+  ip_family: v4
+  dns_query_type: aaaa
+destinations:
+  - name: gateway
+    tags: [lan, core]
+    type: icmp
+    address: 192.168.1.1
+    interval: 200
+    payload_size: 64
+  - type: dns
+    address: 127.0.0.1
+    timeout: 100
+    dns_query_name: example.com
+    dns_query_type: MX
+"#;
+
+    fn check_file(file: &MPingerConfigFile) {
+        let mut config = MPingerConfig::default();
+        file.settings.apply(&mut config).unwrap();
+        assert_eq!(config.ping_interval, 500);
+        assert_eq!(config.timeout, 2000);
+        assert_eq!(config.ping_retries, 2);
+        assert_eq!(config.ip_family, MPingerIpFamily::V4);
+        assert_eq!(config.dns_query_type, MPingerDNSRecordType::AAAA);
+        // not set in the file
+        assert_eq!(config.dns_ttl, MPingerConfig::default().dns_ttl);
+
+        assert_eq!(file.destinations.len(), 2);
+        let gateway = &file.destinations[0];
+        assert_eq!(gateway.name.as_deref(), Some("gateway"));
+        assert_eq!(gateway.tags, ["lan", "core"]);
+        assert_eq!(gateway.probe_type, MPingerProbeType::ICMP);
+        assert_eq!(gateway.address, "192.168.1.1");
+        assert_eq!(
+            gateway.overrides().unwrap(),
+            MPingerConfigOverrides {
+                ping_interval: Some(200),
+                payload_size: Some(64),
+                ..Default::default()
+            }
+        );
+
+        let dns = &file.destinations[1];
+        assert_eq!(dns.name, None);
+        assert!(dns.tags.is_empty());
+        assert_eq!(dns.probe_type, MPingerProbeType::DNS);
+        assert_eq!(
+            dns.overrides().unwrap(),
+            MPingerConfigOverrides {
+                timeout: Some(100),
+                dns_query_name: Some("example.com".to_string()),
+                dns_query_type: Some(MPingerDNSRecordType::MX),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn toml_file() {
+        check_file(&MPingerConfigFile::from_toml(TOML).unwrap());
+    }
+
+    #[test]
+    fn yaml_file() {
+        check_file(&MPingerConfigFile::from_yaml(YAML).unwrap());
+    }
+
+    #[test]
+    fn empty_file() {
+        let file = MPingerConfigFile::from_toml("").unwrap();
+        assert!(file.destinations.is_empty());
+
+        let mut config = MPingerConfig::default();
+        file.settings.apply(&mut config).unwrap();
+        assert_eq!(config.ping_interval, MPingerConfig::default().ping_interval);
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let files = [
+            "colour = \"blue\"",
+            "[settings]\nintervall = 500",
+            "[[destinations]]\ntype = \"icmp\"\naddress = \"192.168.1.1\"\nttl = 64",
+        ];
+        for content in files {
+            let e = MPingerConfigFile::from_toml(content).unwrap_err();
+            assert!(e.to_string().contains("unknown field"), "{}", e);
+        }
+
+        let files = [
+            "colour: blue",
+            "settings:\n  intervall: 500",
+            "destinations:\n  - type: icmp\n    address: 192.168.1.1\n    ttl: 64",
+        ];
+        for content in files {
+            let e = MPingerConfigFile::from_yaml(content).unwrap_err();
+            assert!(e.to_string().contains("unknown field"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn bad_values() {
+        let files = [
+            // not a number
+            "[settings]\ninterval = \"fast\"",
+            "[settings]\nretries = -1",
+            "[settings]\nip_family = \"v5\"",
+            "[[destinations]]\ntype = \"ping\"\naddress = \"192.168.1.1\"",
+            "[[destinations]]\ntype = \"udp\"\naddress = \"192.168.1.1\"\nport = 70000",
+            // the address is required
+            "[[destinations]]\ntype = \"icmp\"",
+        ];
+        for content in files {
+            assert!(
+                MPingerConfigFile::from_toml(content).is_err(),
+                "{}",
+                content
+            );
+        }
+
+        assert!(MPingerConfigFile::from_yaml("settings:\n  resolve_all: maybe").is_err());
+        assert!(MPingerConfigFile::from_yaml("destinations: gateway").is_err());
+    }
+
+    #[test]
+    fn bad_dns_query_type() {
+        // checked when the settings are applied
+        let file = MPingerConfigFile::from_toml("[settings]\ndns_query_type = \"BOGUS\"").unwrap();
+        let e = file
+            .settings
+            .apply(&mut MPingerConfig::default())
+            .unwrap_err();
+        assert!(e.to_string().contains("BOGUS"), "{}", e);
+
+        // or the destination added
+        let file = MPingerConfigFile::from_toml(
+            "[[destinations]]\ntype = \"dns\"\naddress = \"127.0.0.1\"\ndns_query_type = \"BOGUS\"",
+        )
+        .unwrap();
+        assert!(file.destinations[0].overrides().is_err());
+
+        let mut pinger = MPinger::new(MPingerConfig::default());
+        let e = pinger
+            .add_destination_config(&file.destinations[0])
+            .unwrap_err();
+        assert!(e.to_string().contains("Error adding 127.0.0.1"), "{}", e);
+    }
+
+    #[test]
+    fn destination_overrides() {
+        let file = MPingerConfigFile::from_toml(TOML).unwrap();
+        let mut pinger = MPinger::new(MPingerConfig::default());
+        let gateway = pinger
+            .add_destination_config(&file.destinations[0])
+            .unwrap();
+        let dns = pinger
+            .add_destination_config(&file.destinations[1])
+            .unwrap();
+
+        let config: MPingerConfigShared = Arc::new(RwLock::new(MPingerConfig::default()));
+        let gateway = pinger.get_destination_by_id(gateway).unwrap();
+        assert_eq!(gateway.name.as_deref(), Some("gateway"));
+        assert_eq!(gateway.tags, ["lan", "core"]);
+        assert_eq!(gateway.ping_type, MPingerType::ICMPPing);
+        assert_eq!(gateway.ping_interval(&config), Duration::from_millis(200));
+        assert_eq!(gateway.payload_size(), Some(64));
+
+        let dns = pinger.get_destination_by_id(dns).unwrap();
+        assert_eq!(dns.ping_type, MPingerType::DNSQuery);
+        assert_eq!(dns.port, 53);
+        assert_eq!(dns.timeout(&config), Duration::from_millis(100));
+        // the global interval is kept
+        assert_eq!(
+            dns.ping_interval(&config),
+            Duration::from_millis(MPingerConfig::default().ping_interval)
+        );
+        assert_eq!(dns.dns_query_type(&config), MPingerDNSRecordType::MX);
+    }
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir().join(format!("mpinger-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let toml = dir.join("mpinger.toml");
+        let yaml = dir.join("mpinger.YML");
+        let other = dir.join("mpinger.json");
+        std::fs::write(&toml, TOML).unwrap();
+        std::fs::write(&yaml, YAML).unwrap();
+        std::fs::write(&other, "{}").unwrap();
+
+        check_file(&MPingerConfigFile::load(&toml).unwrap());
+        check_file(&MPingerConfigFile::load(&yaml).unwrap());
+        let e = MPingerConfigFile::load(&other).unwrap_err();
+        assert!(
+            e.to_string().contains("Unknown config file format"),
+            "{}",
+            e
+        );
+        let e = MPingerConfigFile::load(dir.join("missing.toml")).unwrap_err();
+        assert!(e.to_string().contains("Error reading"), "{}", e);
+
+        // the settings are applied before the destinations are added
+        let pinger = MPinger::from_config(&toml).unwrap();
+        assert_eq!(pinger.get_ping_interval(), 500);
+        let names: Vec<Option<String>> = (1..=2)
+            .filter_map(|id| pinger.get_destination_by_id(id))
+            .map(|dest| dest.name)
+            .collect();
+        assert_eq!(names, [Some("gateway".to_string()), None]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
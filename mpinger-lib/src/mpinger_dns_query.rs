@@ -0,0 +1,526 @@
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerResult};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{MPingerStopSignal, PingLoop, Runner};
+use crate::mpinger_udp::udp_bind_addr;
+use log::{debug, error};
+use rand::random;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+
+// largest response a resolver sends over UDP to a query without EDNS
+const DNS_UDP_SIZE: usize = 512;
+const DNS_HEADER_SIZE: usize = 12;
+const DNS_FLAG_RESPONSE: u16 = 0x8000;
+const DNS_FLAG_TRUNCATED: u16 = 0x0200;
+const DNS_FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const DNS_CLASS_IN: u16 = 1;
+
+// Queries the destination, a DNS resolver, for the dns_query_name of its overrides
+// or MPingerConfig::dns_query_name
+pub struct MPingerDNSQuery();
+
+impl Runner for MPingerDNSQuery {
+    fn name(&self) -> &str {
+        "DNS query"
+    }
+
+    fn default_port(&self) -> Option<u16> {
+        Some(53)
+    }
+
+    fn start(
+        &self,
+        config: MPingerConfigShared,
+        dest: &MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: &MPingerStopSignal,
+    ) {
+        let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+        while let Some(i) = pings.next_ping() {
+            let (id, query) = match new_dns_query(&config, dest) {
+                Ok(query) => query,
+                Err(e) => {
+                    error!("Error building DNS query: {}", e);
+                    pings.abort(
+                        MPingerMessage::new_error(dest, i, MPingerResult::Error),
+                        &tx,
+                    );
+                    continue;
+                }
+            };
+
+            let send_time = OffsetDateTime::now_utc();
+            let start_time = Instant::now();
+            let deadline = start_time + dest.timeout(&config);
+            let result = dns_query(dest.socket_addr(), &query, id, deadline);
+            pings.report(get_dns_message(dest, i, send_time, start_time, result), &tx);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+            while let Some(i) = pings.next_ping_async().await {
+                let (id, query) = match new_dns_query(&config, dest) {
+                    Ok(query) => query,
+                    Err(e) => {
+                        error!("Error building DNS query: {}", e);
+                        let message = MPingerMessage::new_error(dest, i, MPingerResult::Error);
+                        pings.abort(message, &tx);
+                        continue;
+                    }
+                };
+
+                let send_time = OffsetDateTime::now_utc();
+                let start_time = Instant::now();
+                let result = tokio::time::timeout(
+                    dest.timeout(&config),
+                    dns_query_async(dest.socket_addr(), &query, id),
+                )
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+                pings.report(get_dns_message(dest, i, send_time, start_time, result), &tx);
+            }
+        }))
+    }
+}
+
+// query with a random id for the name and record type of the destination
+fn new_dns_query(
+    config: &MPingerConfigShared,
+    dest: &MPingDestination,
+) -> Result<(u16, Vec<u8>), String> {
+    let name = dest.dns_query_name(config);
+    let qtype = dest.dns_query_type(config).code();
+
+    let id = random::<u16>();
+    Ok((id, build_dns_query(id, &name, qtype)?))
+}
+
+fn get_dns_message(
+    dest: &MPingDestination,
+    ping_nr: usize,
+    send_time: OffsetDateTime,
+    start_time: Instant,
+    result: std::io::Result<DnsResponse>,
+) -> MPingerMessage {
+    let (rtt, result) = match result {
+        Ok(response) => (start_time.elapsed(), response.get_result()),
+        Err(e) => {
+            debug!("Error querying {}: {}", dest.address, e);
+            (Duration::ZERO, MPingerResult::from_io_error(&e))
+        }
+    };
+
+    MPingerMessage::new_ping(dest, ping_nr, send_time, rtt, result)
+}
+
+// query over UDP, and over TCP again if the response doesn't fit in a datagram,
+// both before the deadline
+fn dns_query(
+    sock_addr: SocketAddr,
+    query: &[u8],
+    id: u16,
+    deadline: Instant,
+) -> std::io::Result<DnsResponse> {
+    let response = dns_query_udp(sock_addr, query, id, deadline)?;
+    if !response.truncated {
+        return Ok(response);
+    }
+
+    debug!(
+        "Truncated DNS response from {}, retrying over TCP",
+        sock_addr
+    );
+    dns_query_tcp(sock_addr, query, id, deadline)
+}
+
+// time left until the deadline, an error once it passed
+fn remaining(deadline: Instant) -> std::io::Result<Duration> {
+    match deadline.saturating_duration_since(Instant::now()) {
+        remaining if remaining.is_zero() => Err(std::io::ErrorKind::TimedOut.into()),
+        remaining => Ok(remaining),
+    }
+}
+
+fn dns_query_udp(
+    sock_addr: SocketAddr,
+    query: &[u8],
+    id: u16,
+    deadline: Instant,
+) -> std::io::Result<DnsResponse> {
+    // connected, so ICMP Port Unreachable is reported as connection refused
+    let socket = UdpSocket::bind(udp_bind_addr(sock_addr))?;
+    socket.connect(sock_addr)?;
+    socket.set_write_timeout(Some(remaining(deadline)?))?;
+    socket.send(query)?;
+
+    let mut buffer = [0u8; DNS_UDP_SIZE];
+    loop {
+        socket.set_read_timeout(Some(remaining(deadline)?))?;
+
+        let n = socket.recv(&mut buffer)?;
+        // late responses to previous queries are ignored
+        if let Some(response) = parse_dns_response(&buffer[..n], id) {
+            return Ok(response);
+        }
+    }
+}
+
+fn dns_query_tcp(
+    sock_addr: SocketAddr,
+    query: &[u8],
+    id: u16,
+    deadline: Instant,
+) -> std::io::Result<DnsResponse> {
+    let mut stream = TcpStream::connect_timeout(&sock_addr, remaining(deadline)?)?;
+
+    // messages are prefixed with their length over TCP
+    stream.set_write_timeout(Some(remaining(deadline)?))?;
+    stream.write_all(&(query.len() as u16).to_be_bytes())?;
+    stream.write_all(query)?;
+
+    let mut length = [0u8; 2];
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    stream.read_exact(&mut length)?;
+    let mut buffer = vec![0u8; u16::from_be_bytes(length) as usize];
+    stream.set_read_timeout(Some(remaining(deadline)?))?;
+    stream.read_exact(&mut buffer)?;
+
+    parse_dns_response(&buffer, id)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid DNS response"))
+}
+
+// async counterpart of dns_query, the caller applies the timeout
+#[cfg(feature = "tokio")]
+async fn dns_query_async(
+    sock_addr: SocketAddr,
+    query: &[u8],
+    id: u16,
+) -> std::io::Result<DnsResponse> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let socket = tokio::net::UdpSocket::bind(udp_bind_addr(sock_addr)).await?;
+    socket.connect(sock_addr).await?;
+    socket.send(query).await?;
+
+    let mut buffer = [0u8; DNS_UDP_SIZE];
+    let response = loop {
+        let n = socket.recv(&mut buffer).await?;
+        if let Some(response) = parse_dns_response(&buffer[..n], id) {
+            break response;
+        }
+    };
+    if !response.truncated {
+        return Ok(response);
+    }
+
+    debug!(
+        "Truncated DNS response from {}, retrying over TCP",
+        sock_addr
+    );
+    let mut stream = tokio::net::TcpStream::connect(sock_addr).await?;
+
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+
+    let length = stream.read_u16().await?;
+    let mut buffer = vec![0u8; length as usize];
+    stream.read_exact(&mut buffer).await?;
+
+    parse_dns_response(&buffer, id)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid DNS response"))
+}
+
+struct DnsResponse {
+    pub rcode: u8,
+    // the answer didn't fit, the query has to be sent again over TCP
+    pub truncated: bool,
+}
+
+impl DnsResponse {
+    pub fn get_result(&self) -> MPingerResult {
+        match self.rcode {
+            0 => MPingerResult::Success,
+            rcode => MPingerResult::DnsRcode(rcode),
+        }
+    }
+}
+
+fn build_dns_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, String> {
+    let mut query = Vec::with_capacity(DNS_HEADER_SIZE + name.len() + 6);
+
+    // Header
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&DNS_FLAG_RECURSION_DESIRED.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    query.extend_from_slice(&[0; 6]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    // Question, the name as length prefixed labels
+    let name = name.trim_end_matches('.');
+    if !name.is_empty() {
+        for label in name.split('.') {
+            if label.is_empty() || label.len() > 63 {
+                return Err(format!("Invalid name: {}", name));
+            }
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+    }
+    query.push(0);
+    if query.len() - DNS_HEADER_SIZE > 255 {
+        return Err(format!("Name too long: {}", name));
+    }
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+
+    Ok(query)
+}
+
+// returns None if the message isn't a response to the query with the given id
+fn parse_dns_response(response: &[u8], id: u16) -> Option<DnsResponse> {
+    if response.len() < DNS_HEADER_SIZE {
+        return None;
+    }
+
+    let recv_id = u16::from_be_bytes([response[0], response[1]]);
+    let flags = u16::from_be_bytes([response[2], response[3]]);
+    if recv_id != id || flags & DNS_FLAG_RESPONSE == 0 {
+        return None;
+    }
+
+    Some(DnsResponse {
+        rcode: (flags & 0x000F) as u8,
+        truncated: flags & DNS_FLAG_TRUNCATED != 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::{
+        MPinger, MPingerConfig, MPingerConfigOverrides, MPingerDNSRecordType, MPingerMessageKind,
+        MPingerType,
+    };
+    use std::net::TcpListener;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    const TIMEOUT: u64 = 300; // ms
+
+    // name of the question, the labels joined with dots
+    fn query_name(query: &[u8]) -> String {
+        let mut labels = Vec::new();
+        let mut offset = DNS_HEADER_SIZE;
+        while offset < query.len() && query[offset] != 0 {
+            let len = query[offset] as usize;
+            labels.push(String::from_utf8_lossy(&query[offset + 1..offset + 1 + len]).to_string());
+            offset += len + 1;
+        }
+        labels.join(".")
+    }
+
+    fn response(query: &[u8], flags: u16) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2..4].copy_from_slice(&(DNS_FLAG_RESPONSE | flags).to_be_bytes());
+        response
+    }
+
+    // Resolver on 127.0.0.1 answering by query name:
+    //   ok.test        NOERROR
+    //   nx.test        NXDOMAIN
+    //   tc.test        truncated REFUSED over UDP, NOERROR over TCP
+    //   slow.test      no answer
+    //   slow-tc.test   truncated over UDP after half the timeout, no answer over TCP
+    fn start_stub_resolver() -> SocketAddr {
+        let (udp, tcp) = loop {
+            let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+            if let Ok(tcp) = TcpListener::bind(udp.local_addr().unwrap()) {
+                break (udp, tcp);
+            }
+        };
+        let addr = udp.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut buffer = [0u8; DNS_UDP_SIZE];
+            while let Ok((n, from)) = udp.recv_from(&mut buffer) {
+                let query = &buffer[..n];
+                let flags = match query_name(query).as_str() {
+                    "ok.test" => 0,
+                    "nx.test" => 3,
+                    "tc.test" => DNS_FLAG_TRUNCATED | 5,
+                    "slow-tc.test" => {
+                        thread::sleep(Duration::from_millis(TIMEOUT / 2));
+                        DNS_FLAG_TRUNCATED
+                    }
+                    _ => continue,
+                };
+                udp.send_to(&response(query, flags), from).unwrap();
+            }
+        });
+
+        thread::spawn(move || {
+            for stream in tcp.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    let mut length = [0u8; 2];
+                    stream.read_exact(&mut length).unwrap();
+                    let mut query = vec![0u8; u16::from_be_bytes(length) as usize];
+                    stream.read_exact(&mut query).unwrap();
+
+                    if query_name(&query) != "tc.test" {
+                        thread::sleep(Duration::from_millis(TIMEOUT * 4));
+                        return;
+                    }
+                    let response = response(&query, 0);
+                    stream
+                        .write_all(&(response.len() as u16).to_be_bytes())
+                        .unwrap();
+                    stream.write_all(&response).unwrap();
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn new_pinger(resolver: SocketAddr, name: &str) -> MPinger {
+        let mut pinger = MPinger::new(MPingerConfig {
+            timeout: TIMEOUT,
+            ..Default::default()
+        });
+        pinger
+            .add_destination_with_overrides(
+                MPingerType::DNSQuery,
+                &resolver.to_string(),
+                MPingerConfigOverrides {
+                    dns_query_name: Some(name.to_string()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        pinger
+    }
+
+    // result of a single query and how long the ping took
+    fn query(resolver: SocketAddr, name: &str) -> (MPingerResult, Duration) {
+        let start_time = Instant::now();
+        let result = new_pinger(resolver, name)
+            .start(1)
+            .find(|message| message.kind == MPingerMessageKind::Ping)
+            .unwrap()
+            .result;
+        (result, start_time.elapsed())
+    }
+
+    #[cfg(feature = "tokio")]
+    fn query_async(resolver: SocketAddr, name: &str) -> (MPingerResult, Duration) {
+        use futures_core::Stream;
+        use std::pin::Pin;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let start_time = Instant::now();
+            let mut stream = new_pinger(resolver, name).start_async(1);
+            loop {
+                let message = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))
+                    .await
+                    .unwrap();
+                if message.kind == MPingerMessageKind::Ping {
+                    return (message.result, start_time.elapsed());
+                }
+            }
+        })
+    }
+
+    // the same results with threads and tokio
+    fn check_query(name: &str, expected: MPingerResult) -> Vec<Duration> {
+        let resolver = start_stub_resolver();
+
+        let (result, elapsed) = query(resolver, name);
+        assert_eq!(result, expected, "{}", name);
+        #[allow(unused_mut)]
+        let mut times = vec![elapsed];
+
+        #[cfg(feature = "tokio")]
+        {
+            let (result, elapsed) = query_async(resolver, name);
+            assert_eq!(result, expected, "{} (tokio)", name);
+            times.push(elapsed);
+        }
+
+        times
+    }
+
+    #[test]
+    fn udp_answer() {
+        check_query("ok.test", MPingerResult::Success);
+    }
+
+    #[test]
+    fn nxdomain() {
+        check_query("nx.test", MPingerResult::DnsRcode(3));
+    }
+
+    #[test]
+    fn truncated_answer_falls_back_to_tcp() {
+        check_query("tc.test", MPingerResult::Success);
+    }
+
+    #[test]
+    fn timeout() {
+        for elapsed in check_query("slow.test", MPingerResult::Timeout) {
+            assert!(elapsed >= Duration::from_millis(TIMEOUT));
+        }
+    }
+
+    #[test]
+    fn tcp_fallback_shares_the_timeout() {
+        for elapsed in check_query("slow-tc.test", MPingerResult::Timeout) {
+            assert!(
+                elapsed < Duration::from_millis(TIMEOUT * 3 / 2),
+                "{:?}",
+                elapsed
+            );
+        }
+    }
+
+    #[test]
+    fn query_of_the_destination() {
+        let config = Arc::new(RwLock::new(MPingerConfig::default()));
+        let mut pinger = MPinger::new(MPingerConfig::default());
+        let id = pinger
+            .add_destination_with_overrides(
+                MPingerType::DNSQuery,
+                "127.0.0.1",
+                MPingerConfigOverrides {
+                    dns_query_name: Some("mail.example.org".to_string()),
+                    dns_query_type: Some(MPingerDNSRecordType::MX),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let dest = pinger.get_destination_by_id(id).unwrap();
+
+        let (id, query) = new_dns_query(&config, &dest).unwrap();
+        assert_eq!(query, build_dns_query(id, "mail.example.org", 15).unwrap());
+    }
+}
@@ -1,132 +1,1074 @@
-use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerType};
-use log::{debug, error};
+use crate::mpinger::{
+    MPingDestination, MPingerConfigShared, MPingerDetails, MPingerHttpDetails, MPingerHttpRequest,
+    MPingerMessage, MPingerResult, MPingerTlsDetails,
+};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{MPingerStopSignal, PingLoop, Runner};
+#[cfg(feature = "tls")]
+use crate::mpinger_tls::tls_handshake;
+#[cfg(all(feature = "tls", feature = "tokio"))]
+use crate::mpinger_tls::tls_handshake_async;
+use crate::utils;
+use log::debug;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream};
 use std::sync::mpsc;
-use std::thread;
-use std::time::Instant;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const HTTP_BUFFER_SIZE: usize = 4096;
+// status line and headers larger than this are rejected
+const HTTP_MAX_HEAD_SIZE: usize = 64 * 1024;
+// only this much of the body is kept to be matched with MPingerHttpRequest::expected_body
+const HTTP_MAX_BODY_SIZE: usize = 1024 * 1024;
+// longest delay between pings failing in a row, ms
+const HTTP_MAX_RETRY_DELAY: u64 = 30_000;
 
 pub struct MPingerHTTPKeepAlive();
 
-impl MPingerHTTPKeepAlive {
-    pub fn start(
+impl Runner for MPingerHTTPKeepAlive {
+    fn name(&self) -> &str {
+        "HTTP Keep Alive"
+    }
+
+    fn start(
+        &self,
         config: MPingerConfigShared,
         dest: &MPingDestination,
         tx: mpsc::Sender<MPingerMessage>,
         count: usize,
+        stop_signal: &MPingerStopSignal,
     ) {
-        let req = format!("GET / HTTP/1.1\r\nHost: {}\r\n\r\n", dest.host);
+        let conn = HttpConnection::new(dest.socket_addr());
+        run_http(config, dest, tx, count, stop_signal, conn);
+    }
 
-        let timeout = std::time::Duration::from_millis(config.read().unwrap().timeout);
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        let conn = HttpConnection::new(dest.socket_addr());
+        Some(Box::pin(run_http_async(
+            config,
+            dest,
+            tx,
+            count,
+            stop_signal,
+            conn,
+        )))
+    }
+}
 
-        let sock = match dest.sock_addr.as_socket() {
-            Some(addr) => addr,
-            None => {
-                error!("Invalid socket address");
-                let _ = tx.send(MPingerMessage {
-                    destination_id: dest.id,
-                    ping_nr: 0,
-                    runner_type: MPingerType::HTTPKeepAlive,
-                    start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                    duration: 0,
-                    is_error: true,
-                });
-                return;
+// ping loop of the HTTP and HTTPS keepalive runners
+pub(crate) fn run_http(
+    config: MPingerConfigShared,
+    dest: &MPingDestination,
+    tx: mpsc::Sender<MPingerMessage>,
+    count: usize,
+    stop_signal: &MPingerStopSignal,
+    mut conn: HttpConnection<Box<dyn HttpStream>>,
+) {
+    let request = dest.http_request.clone().unwrap_or_default();
+    let req = build_http_request(dest, &request, conn.default_port());
+
+    // failed pings in a row, the next attempt is delayed more and more
+    let mut failures = 0;
+
+    let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+    while let Some(i) = pings.next_ping() {
+        let send_time = OffsetDateTime::now_utc();
+        let result = conn.ping(&config, dest, &request, &req);
+        let message = get_http_message(dest, &request, i, send_time, result, &mut failures);
+        pings.report_with_delay(message, &tx, get_ping_delay(&config, dest, failures));
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) async fn run_http_async(
+    config: MPingerConfigShared,
+    dest: &MPingDestination,
+    tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+    count: usize,
+    stop_signal: &MPingerStopSignal,
+    mut conn: HttpConnection<Box<dyn AsyncHttpStream>>,
+) {
+    let request = dest.http_request.clone().unwrap_or_default();
+    let req = build_http_request(dest, &request, conn.default_port());
+
+    let mut failures = 0;
+
+    let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+    while let Some(i) = pings.next_ping_async().await {
+        let send_time = OffsetDateTime::now_utc();
+        let result = conn.ping_async(&config, dest, &request, &req).await;
+        let message = get_http_message(dest, &request, i, send_time, result, &mut failures);
+        pings.report_with_delay(message, &tx, get_ping_delay(&config, dest, failures));
+    }
+}
+
+type HttpPingResult = Result<(Duration, MPingerHttpDetails, Vec<u8>), MPingerResult>;
+
+fn get_http_message(
+    dest: &MPingDestination,
+    request: &MPingerHttpRequest,
+    ping_nr: usize,
+    send_time: OffsetDateTime,
+    result: HttpPingResult,
+    failures: &mut u32,
+) -> MPingerMessage {
+    match result {
+        Ok((rtt, response, body)) => {
+            *failures = 0;
+            let result = get_http_result(dest, request, &response, &body);
+            MPingerMessage::new_ping(dest, ping_nr, send_time, rtt, result)
+                .with_details(MPingerDetails::Http(response))
+        }
+        Err(result) => {
+            *failures += 1;
+            MPingerMessage::new_ping(dest, ping_nr, send_time, Duration::ZERO, result)
+        }
+    }
+}
+
+// plain or TLS connection
+pub(crate) trait HttpStream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> HttpStream for T {}
+
+#[cfg(feature = "tokio")]
+pub(crate) trait AsyncHttpStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[cfg(feature = "tokio")]
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncHttpStream for T {}
+
+// Connection of an HTTP destination, opened again by the next ping once dropped,
+// over a blocking stream or a tokio one
+pub(crate) struct HttpConnection<S> {
+    // address of the destination when the connection was opened
+    dest_sock: SocketAddr,
+    // address the connection was opened to, the one the hostname resolved to
+    sock: SocketAddr,
+    stream: Option<S>,
+    // connections opened so far
+    connections: usize,
+    // HTTPS connections do a TLS handshake once connected
+    #[cfg(feature = "tls")]
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+    // session of the current TLS connection
+    tls: Option<MPingerTlsDetails>,
+}
+
+impl<S> HttpConnection<S> {
+    pub fn new(sock: SocketAddr) -> Self {
+        Self {
+            dest_sock: sock,
+            sock,
+            stream: None,
+            connections: 0,
+            #[cfg(feature = "tls")]
+            tls_config: None,
+            tls: None,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn new_tls(sock: SocketAddr, tls_config: Arc<rustls::ClientConfig>) -> Self {
+        Self {
+            tls_config: Some(tls_config),
+            ..HttpConnection::new(sock)
+        }
+    }
+
+    // port left out of the Host header
+    fn default_port(&self) -> u16 {
+        #[cfg(feature = "tls")]
+        if self.tls_config.is_some() {
+            return 443;
+        }
+        80
+    }
+
+    // also when the hostname was resolved to another address, don't keep pinging the old one
+    fn needs_connect(&self, dest: &MPingDestination) -> bool {
+        self.stream.is_none() || dest.socket_addr() != self.dest_sock
+    }
+
+    // hostnames are looked up by every new connection, like curl does, but the other addresses
+    // of a hostname (MPingerConfig::resolve_all) are pinged as they are
+    fn needs_lookup(dest: &MPingDestination) -> bool {
+        dest.is_hostname() && dest.parent_id.is_none()
+    }
+
+    // the connection is dropped on any error
+    fn ping_done(
+        &mut self,
+        request: &MPingerHttpRequest,
+        result: HttpPingResult,
+    ) -> HttpPingResult {
+        if result.is_err() || request.new_connection {
+            self.stream = None;
+        }
+
+        result
+    }
+
+    // returns the round trip time, the response and its body if the request expects one
+    fn complete(
+        &mut self,
+        request: &MPingerHttpRequest,
+        mut exchange: HttpExchange,
+        mut response: MPingerHttpDetails,
+    ) -> (Duration, MPingerHttpDetails, Vec<u8>) {
+        let rtt = exchange.request_start.elapsed();
+
+        response.dns_time = exchange.dns_time;
+        response.connect_time = exchange.connect_time;
+        response.request_time = exchange.request_time;
+        response.first_byte_time = exchange.first_byte_time.unwrap_or_default();
+        response.total_time = exchange.start_time.elapsed();
+        response.tls_time = exchange.tls_time;
+        response.reconnects = get_reconnects(request, self.connections);
+        response.tls = self.tls.clone();
+
+        if response.connection_close {
+            self.stream = None;
+        }
+
+        (rtt, response, exchange.parser.take_body())
+    }
+}
+
+impl HttpConnection<Box<dyn HttpStream>> {
+    // start TLS on HTTPS connections
+    fn start_tls(
+        &mut self,
+        dest: &MPingDestination,
+        stream: TcpStream,
+        connect_time: Duration,
+    ) -> Result<Box<dyn HttpStream>, MPingerResult> {
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = self.tls_config.as_ref() {
+            let (stream, tls) = tls_handshake(stream, dest, tls_config, connect_time)?;
+            self.tls = Some(tls);
+            return Ok(Box::new(stream));
+        }
+        let _ = (dest, connect_time);
+
+        Ok(Box::new(stream))
+    }
+
+    fn ping(
+        &mut self,
+        config: &MPingerConfigShared,
+        dest: &MPingDestination,
+        request: &MPingerHttpRequest,
+        req: &str,
+    ) -> HttpPingResult {
+        let result = self.send_request(config, dest, request, req);
+        self.ping_done(request, result)
+    }
+
+    fn send_request(
+        &mut self,
+        config: &MPingerConfigShared,
+        dest: &MPingDestination,
+        request: &MPingerHttpRequest,
+        req: &str,
+    ) -> HttpPingResult {
+        let mut exchange = HttpExchange::new(request);
+
+        if self.needs_connect(dest) {
+            self.dest_sock = dest.socket_addr();
+            self.sock = self.dest_sock;
+            if Self::needs_lookup(dest) {
+                let ips = utils::resolve_host(&dest.host, dest.port, dest.ip_family);
+                self.sock = exchange.resolved(dest, ips)?;
             }
-        };
 
-        let mut stream = match TcpStream::connect_timeout(&sock, timeout) {
-            Ok(stream) => stream,
-            Err(e) => {
-                error!("Error connecting: {}", e);
-                let _ = tx.send(MPingerMessage {
-                    destination_id: dest.id,
-                    ping_nr: 0,
-                    runner_type: MPingerType::HTTPKeepAlive,
-                    start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                    duration: 0,
-                    is_error: true,
-                });
-                return;
+            let connect_start = Instant::now();
+            let stream = match connect(dest.timeout(config), self.sock) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Error connecting to {}: {}", self.sock, e);
+                    return Err(MPingerResult::from_io_error(&e));
+                }
+            };
+            self.connections += 1;
+            exchange.connected();
+
+            self.stream = Some(self.start_tls(dest, stream, connect_start.elapsed())?);
+            if self.tls.is_some() {
+                exchange.tls_done();
+            }
+        }
+        let stream = self.stream.as_mut().unwrap();
+
+        exchange.request_start = Instant::now();
+        if let Err(e) = stream.write_all(req.as_bytes()) {
+            debug!("Error sending HTTP Request: {}", e);
+            return Err(MPingerResult::SendError);
+        }
+        exchange.request_sent();
+
+        // Read the whole response, the round trip ends with its last byte
+        let mut buffer = [0; HTTP_BUFFER_SIZE];
+        let response = loop {
+            let n = match stream.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) => {
+                    debug!("Error reading HTTP Response: {}", e);
+                    return Err(MPingerResult::from_io_error(&e));
+                }
+            };
+            if let Some(response) = exchange.received(&buffer[..n])? {
+                break response;
             }
         };
-        stream
-            .set_read_timeout(Some(std::time::Duration::from_millis(
-                config.read().unwrap().timeout,
-            )))
-            .unwrap();
-        stream
-            .set_write_timeout(Some(std::time::Duration::from_millis(
-                config.read().unwrap().timeout,
-            )))
-            .unwrap();
-
-        let mut i = 0;
-        loop {
-            let start_time = Instant::now();
 
-            let result = stream.write_all(req.as_bytes());
-            if result.is_err() {
-                debug!("Error sending HTTP Request: {}", result.err().unwrap());
-                let _ = tx.send(MPingerMessage {
-                    destination_id: dest.id,
-                    ping_nr: i,
-                    runner_type: MPingerType::HTTPKeepAlive,
-                    start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                    duration: 0,
-                    is_error: true,
-                });
-                return;
+        Ok(self.complete(request, exchange, response))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl HttpConnection<Box<dyn AsyncHttpStream>> {
+    async fn start_tls_async(
+        &mut self,
+        config: &MPingerConfigShared,
+        dest: &MPingDestination,
+        stream: tokio::net::TcpStream,
+        connect_time: Duration,
+    ) -> Result<Box<dyn AsyncHttpStream>, MPingerResult> {
+        #[cfg(feature = "tls")]
+        if let Some(tls_config) = self.tls_config.as_ref() {
+            let (stream, tls) =
+                tls_handshake_async(config, stream, dest, tls_config, connect_time).await?;
+            self.tls = Some(tls);
+            return Ok(Box::new(stream));
+        }
+        let _ = (config, dest, connect_time);
+
+        Ok(Box::new(stream))
+    }
+
+    async fn ping_async(
+        &mut self,
+        config: &MPingerConfigShared,
+        dest: &MPingDestination,
+        request: &MPingerHttpRequest,
+        req: &str,
+    ) -> HttpPingResult {
+        let result = self.send_request_async(config, dest, request, req).await;
+        self.ping_done(request, result)
+    }
+
+    async fn send_request_async(
+        &mut self,
+        config: &MPingerConfigShared,
+        dest: &MPingDestination,
+        request: &MPingerHttpRequest,
+        req: &str,
+    ) -> HttpPingResult {
+        let mut exchange = HttpExchange::new(request);
+
+        if self.needs_connect(dest) {
+            self.dest_sock = dest.socket_addr();
+            self.sock = self.dest_sock;
+            if Self::needs_lookup(dest) {
+                let ips = tokio::net::lookup_host((dest.host.as_str(), dest.port))
+                    .await
+                    .map(|addrs| addrs.map(|addr| addr.ip()).collect())
+                    .map_err(|e| e.to_string());
+                self.sock = exchange.resolved(dest, ips)?;
+            }
+
+            let connect_start = Instant::now();
+            let stream = match connect_async(dest.timeout(config), self.sock).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("Error connecting to {}: {}", self.sock, e);
+                    return Err(MPingerResult::from_io_error(&e));
+                }
+            };
+            self.connections += 1;
+            exchange.connected();
+
+            let stream = self
+                .start_tls_async(config, dest, stream, connect_start.elapsed())
+                .await?;
+            self.stream = Some(stream);
+            if self.tls.is_some() {
+                exchange.tls_done();
             }
+        }
+        let stream = self.stream.as_mut().unwrap();
+        let timeout = dest.timeout(config);
+
+        exchange.request_start = Instant::now();
+        let result = tokio::time::timeout(timeout, stream.write_all(req.as_bytes()))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        if let Err(e) = result {
+            debug!("Error sending HTTP Request: {}", e);
+            return Err(MPingerResult::SendError);
+        }
+        exchange.request_sent();
 
-            // Read the response
-            // TODO! read Content-Length and read only that much data
-            // TODO! add support for chunked encoding
-            const BUFFER_SIZE: usize = 4096;
-            let mut buffer = [0; BUFFER_SIZE];
-            let mut n = match stream.read(&mut buffer) {
+        let mut buffer = [0; HTTP_BUFFER_SIZE];
+        let response = loop {
+            let n = match tokio::time::timeout(timeout, stream.read(&mut buffer))
+                .await
+                .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+            {
                 Ok(n) => n,
                 Err(e) => {
                     debug!("Error reading HTTP Response: {}", e);
-                    let _ = tx.send(MPingerMessage {
-                        destination_id: dest.id,
-                        ping_nr: i,
-                        runner_type: MPingerType::HTTPKeepAlive,
-                        start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                        duration: 0,
-                        is_error: true,
-                    });
-                    return;
+                    return Err(MPingerResult::from_io_error(&e));
                 }
             };
+            if let Some(response) = exchange.received(&buffer[..n])? {
+                break response;
+            }
+        };
+
+        Ok(self.complete(request, exchange, response))
+    }
+}
+
+// Phases of a single HTTP ping, measured from its start like curl's -w times,
+// and the response read so far
+struct HttpExchange {
+    start_time: Instant,
+    dns_time: Option<Duration>,
+    connect_time: Option<Duration>,
+    tls_time: Option<Duration>,
+    // the round trip starts with the request
+    request_start: Instant,
+    request_time: Duration,
+    first_byte_time: Option<Duration>,
+    parser: HttpResponseParser,
+}
+
+impl HttpExchange {
+    fn new(request: &MPingerHttpRequest) -> Self {
+        let start_time = Instant::now();
+        Self {
+            start_time,
+            dns_time: None,
+            connect_time: None,
+            tls_time: None,
+            request_start: start_time,
+            request_time: Duration::ZERO,
+            first_byte_time: None,
+            parser: HttpResponseParser::new(request),
+        }
+    }
+
+    // connect to the address of the destination while it's still one of the hostname
+    fn resolved(
+        &mut self,
+        dest: &MPingDestination,
+        ips: Result<Vec<IpAddr>, String>,
+    ) -> Result<SocketAddr, MPingerResult> {
+        self.dns_time = Some(self.start_time.elapsed());
 
-            let duration = Instant::now().duration_since(start_time).as_millis() as u32;
+        let ips = ips.map_err(|e| {
+            debug!("Error resolving {}: {}", dest.host, e);
+            MPingerResult::DnsFailure
+        })?;
+        let ip = match ips.contains(&dest.ip()) {
+            true => Some(dest.ip()),
+            false => ips.into_iter().find(|ip| dest.ip_family.matches(ip)),
+        };
 
-            while n >= BUFFER_SIZE {
-                let mut buffer = [0; BUFFER_SIZE];
-                n = stream.read(&mut buffer).unwrap_or(0);
+        match ip {
+            Some(ip) => Ok(SocketAddr::new(ip, dest.port)),
+            None => {
+                debug!("No address resolved for {}", dest.host);
+                Err(MPingerResult::DnsFailure)
             }
+        }
+    }
 
-            let result = tx.send(MPingerMessage {
-                destination_id: dest.id,
-                ping_nr: i,
-                runner_type: MPingerType::HTTPKeepAlive,
-                start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                duration: duration as u64,
-                is_error: false,
-            });
-            if result.is_err() {
-                debug!("Error sending message: {:?}", result);
+    fn connected(&mut self) {
+        self.connect_time = Some(self.start_time.elapsed());
+    }
+
+    fn tls_done(&mut self) {
+        self.tls_time = Some(self.start_time.elapsed());
+    }
+
+    fn request_sent(&mut self) {
+        self.request_time = self.start_time.elapsed();
+    }
+
+    // feed what was read from the connection, returns the response once complete
+    fn received(&mut self, data: &[u8]) -> Result<Option<MPingerHttpDetails>, MPingerResult> {
+        if !data.is_empty() && self.first_byte_time.is_none() {
+            self.first_byte_time = Some(self.start_time.elapsed());
+        }
+
+        self.parser.feed(data).map_err(|e| {
+            debug!("Invalid HTTP Response: {}", e);
+            MPingerResult::Error
+        })
+    }
+}
+
+pub(crate) fn connect(timeout: Duration, sock: SocketAddr) -> std::io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&sock, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    Ok(stream)
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) async fn connect_async(
+    timeout: Duration,
+    sock: SocketAddr,
+) -> std::io::Result<tokio::net::TcpStream> {
+    tokio::time::timeout(timeout, tokio::net::TcpStream::connect(sock))
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+}
+
+// the port is left out of the Host header when it's the default one of the scheme
+pub(crate) fn build_http_request(
+    dest: &MPingDestination,
+    request: &MPingerHttpRequest,
+    default_port: u16,
+) -> String {
+    let mut req = format!("{} {} HTTP/1.1\r\n", request.method, request.path);
+    if request.new_connection {
+        req += "Connection: close\r\n";
+    }
+
+    if !request
+        .headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+    {
+        let host = if dest.host.parse::<Ipv6Addr>().is_ok() {
+            format!("[{}]", dest.host)
+        } else {
+            dest.host.clone()
+        };
+        if dest.port == default_port {
+            req += &format!("Host: {}\r\n", host);
+        } else {
+            req += &format!("Host: {}:{}\r\n", host, dest.port);
+        }
+    }
+    for (name, value) in request.headers.iter() {
+        req += &format!("{}: {}\r\n", name, value);
+    }
+
+    match request.body.as_ref() {
+        Some(body) => req += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body),
+        None => req += "\r\n",
+    }
+
+    req
+}
+
+// the delay between pings doubles with every failure in a row, up to HTTP_MAX_RETRY_DELAY
+pub(crate) fn get_ping_delay(
+    config: &MPingerConfigShared,
+    dest: &MPingDestination,
+    failures: u32,
+) -> Duration {
+    let ping_interval = dest.ping_interval(config);
+    if failures == 0 {
+        return ping_interval;
+    }
+
+    let ping_interval = ping_interval.as_millis() as u64;
+    let max_delay = HTTP_MAX_RETRY_DELAY.max(ping_interval);
+    let delay = ping_interval.saturating_mul(1 << failures.min(16));
+    Duration::from_millis(delay.min(max_delay))
+}
+
+// connections opened again after the first one, which is every one when a new connection
+// is opened for every ping
+pub(crate) fn get_reconnects(request: &MPingerHttpRequest, connections: usize) -> usize {
+    if request.new_connection {
+        0
+    } else {
+        connections.saturating_sub(1)
+    }
+}
+
+// 2xx and 3xx responses are successful pings, unless another status or body is expected
+pub(crate) fn get_http_result(
+    dest: &MPingDestination,
+    request: &MPingerHttpRequest,
+    response: &MPingerHttpDetails,
+    body: &[u8],
+) -> MPingerResult {
+    let status = response.status;
+    let status_ok = match request.expected_status {
+        Some(expected_status) => status == expected_status,
+        None => (200..400).contains(&status),
+    };
+    if !status_ok {
+        return MPingerResult::HttpStatus(status);
+    }
+
+    if let Some(expected_body) = request.expected_body.as_ref() {
+        if !expected_body.matches(&String::from_utf8_lossy(body)) {
+            return MPingerResult::HttpBodyMismatch;
+        }
+    }
+
+    // certificate checks of HTTPS connections
+    match response.tls.as_ref() {
+        Some(tls) => dest.check_tls(tls),
+        None => MPingerResult::Success,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum HttpParserState {
+    // status line and headers
+    Head,
+    // Content-Length bytes left
+    Body(usize),
+    ChunkSize,
+    // bytes left in the current chunk
+    ChunkData(usize),
+    // CRLF after the chunk data
+    ChunkEnd,
+    // optional trailer fields after the last chunk
+    Trailers,
+    // no length given, the body ends when the connection is closed
+    UntilClose,
+}
+
+// Incremental HTTP/1.1 response parser, fed with what is read from the connection
+pub(crate) struct HttpResponseParser {
+    buffer: Vec<u8>,
+    state: HttpParserState,
+    response: MPingerHttpDetails,
+    // the response to a HEAD request has no body
+    is_head: bool,
+    // the body is only kept when it has to be matched
+    keep_body: bool,
+    body: Vec<u8>,
+}
+
+impl HttpResponseParser {
+    pub fn new(request: &MPingerHttpRequest) -> Self {
+        Self {
+            buffer: Vec::new(),
+            state: HttpParserState::Head,
+            response: MPingerHttpDetails::default(),
+            is_head: request.is_head(),
+            keep_body: request.expected_body.is_some(),
+            body: Vec::new(),
+        }
+    }
+
+    // body of the parsed response, empty unless the request expects a body
+    pub fn take_body(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.body)
+    }
+
+    // returns the response once complete, an empty read means the connection was closed
+    pub fn feed(&mut self, data: &[u8]) -> Result<Option<MPingerHttpDetails>, String> {
+        self.response.bytes_received += data.len();
+        if data.is_empty() {
+            return match self.state {
+                HttpParserState::UntilClose => Ok(Some(self.take_response())),
+                _ => Err("Connection closed before the end of the response".to_string()),
+            };
+        }
+        self.buffer.extend_from_slice(data);
+
+        loop {
+            match self.state {
+                HttpParserState::Head => {
+                    let end = match find(&self.buffer, b"\r\n\r\n") {
+                        Some(end) => end,
+                        None if self.buffer.len() > HTTP_MAX_HEAD_SIZE => {
+                            return Err("Response head too large".to_string());
+                        }
+                        None => return Ok(None),
+                    };
+                    let head = self.buffer.drain(..end + 4).collect::<Vec<u8>>();
+                    self.parse_head(&head[..end])?;
+                }
+                HttpParserState::Body(remaining) => {
+                    let n = self.consume_body(remaining);
+                    if n < remaining {
+                        self.state = HttpParserState::Body(remaining - n);
+                        return Ok(None);
+                    }
+                    return Ok(Some(self.take_response()));
+                }
+                HttpParserState::ChunkData(remaining) => {
+                    let n = self.consume_body(remaining);
+                    if n < remaining {
+                        self.state = HttpParserState::ChunkData(remaining - n);
+                        return Ok(None);
+                    }
+                    self.state = HttpParserState::ChunkEnd;
+                }
+                HttpParserState::ChunkSize => {
+                    let line = match self.take_line()? {
+                        Some(line) => line,
+                        None => return Ok(None),
+                    };
+                    // chunk extensions after ';' are ignored
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = usize::from_str_radix(size, 16)
+                        .map_err(|_| format!("Invalid chunk size: {}", line))?;
+
+                    self.state = match size {
+                        0 => HttpParserState::Trailers,
+                        size => HttpParserState::ChunkData(size),
+                    };
+                }
+                HttpParserState::ChunkEnd => match self.take_line()? {
+                    Some(line) if line.is_empty() => self.state = HttpParserState::ChunkSize,
+                    Some(_) => return Err("Missing CRLF after chunk data".to_string()),
+                    None => return Ok(None),
+                },
+                HttpParserState::Trailers => match self.take_line()? {
+                    Some(line) if line.is_empty() => return Ok(Some(self.take_response())),
+                    Some(_) => {}
+                    None => return Ok(None),
+                },
+                HttpParserState::UntilClose => {
+                    self.consume_body(self.buffer.len());
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    fn parse_head(&mut self, head: &[u8]) -> Result<(), String> {
+        let head = std::str::from_utf8(head).map_err(|_| "Invalid response head".to_string())?;
+        let mut lines = head.split("\r\n");
+
+        // e.g. "HTTP/1.1 200 OK"
+        let status_line = lines.next().unwrap_or("");
+        let mut parts = status_line.splitn(3, ' ');
+        let version = parts.next().unwrap_or("");
+        if !version.starts_with("HTTP/1.") {
+            return Err(format!("Invalid status line: {}", status_line));
+        }
+        let status = parts
+            .next()
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| format!("Invalid status line: {}", status_line))?;
+
+        // HTTP/1.0 closes the connection unless asked to keep it alive
+        let mut connection_close = version == "HTTP/1.0";
+        let mut content_length = None;
+        let mut chunked = false;
+        for line in lines {
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|_| format!("Invalid Content-Length: {}", value))?,
+                );
+            } else if name.eq_ignore_ascii_case("transfer-encoding") {
+                chunked = value
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"));
+            } else if name.eq_ignore_ascii_case("connection") {
+                for option in value.split(',') {
+                    if option.trim().eq_ignore_ascii_case("close") {
+                        connection_close = true;
+                    } else if option.trim().eq_ignore_ascii_case("keep-alive") {
+                        connection_close = false;
+                    }
+                }
             }
+        }
 
-            i += 1;
-            if count > 0 && i >= count {
+        // interim responses, e.g. 100 Continue, are followed by the final one
+        if (100..200).contains(&status) && status != 101 {
+            return Ok(());
+        }
+
+        self.response.status = status;
+        self.response.connection_close = connection_close;
+        self.state =
+            if self.is_head || status == 204 || status == 304 || (100..200).contains(&status) {
+                HttpParserState::Body(0)
+            } else if chunked {
+                HttpParserState::ChunkSize
+            } else if let Some(content_length) = content_length {
+                HttpParserState::Body(content_length)
+            } else {
+                self.response.connection_close = true;
+                HttpParserState::UntilClose
+            };
+
+        Ok(())
+    }
+
+    // consume up to max bytes of the body, returns how many were buffered
+    fn consume_body(&mut self, max: usize) -> usize {
+        let n = max.min(self.buffer.len());
+        let data = self.buffer.drain(..n);
+        if self.keep_body && self.body.len() < HTTP_MAX_BODY_SIZE {
+            let keep = n.min(HTTP_MAX_BODY_SIZE - self.body.len());
+            self.body.extend(data.take(keep));
+        }
+        self.response.body_size += n;
+        n
+    }
+
+    fn take_line(&mut self) -> Result<Option<String>, String> {
+        let end = match find(&self.buffer, b"\r\n") {
+            Some(end) => end,
+            None if self.buffer.len() > HTTP_MAX_HEAD_SIZE => {
+                return Err("Line too long".to_string());
+            }
+            None => return Ok(None),
+        };
+
+        let line = self.buffer.drain(..end + 2).collect::<Vec<u8>>();
+        Ok(Some(String::from_utf8_lossy(&line[..end]).into_owned()))
+    }
+
+    fn take_response(&mut self) -> MPingerHttpDetails {
+        if !self.buffer.is_empty() {
+            debug!(
+                "Ignoring {} bytes after the HTTP response",
+                self.buffer.len()
+            );
+        }
+        std::mem::take(&mut self.response)
+    }
+}
+
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::MPingerHttpBodyMatch;
+
+    // the body is kept to check it
+    fn get_request() -> MPingerHttpRequest {
+        MPingerHttpRequest {
+            expected_body: Some(MPingerHttpBodyMatch::Contains(String::new())),
+            ..Default::default()
+        }
+    }
+
+    fn head_request() -> MPingerHttpRequest {
+        MPingerHttpRequest {
+            method: "HEAD".to_string(),
+            ..get_request()
+        }
+    }
+
+    // feed the response in pieces of size bytes, until the parser returns a response
+    fn feed_split(
+        parser: &mut HttpResponseParser,
+        data: &[u8],
+        size: usize,
+    ) -> Result<Option<MPingerHttpDetails>, String> {
+        for piece in data.chunks(size) {
+            if let Some(response) = parser.feed(piece)? {
+                return Ok(Some(response));
+            }
+        }
+        Ok(None)
+    }
+
+    // the response and its body, which are the same whatever the read sizes
+    fn parse(request: &MPingerHttpRequest, data: &[u8]) -> (MPingerHttpDetails, Vec<u8>) {
+        let mut parsed: Option<(MPingerHttpDetails, Vec<u8>)> = None;
+        for size in [1, 2, 5, 13, data.len()] {
+            let mut parser = HttpResponseParser::new(request);
+            let response = feed_split(&mut parser, data, size)
+                .unwrap()
+                .unwrap_or_else(|| panic!("incomplete response with {} byte reads", size));
+            let result = (response, parser.take_body());
+            if let Some(parsed) = parsed.as_ref() {
+                assert_eq!(&result, parsed, "{} byte reads", size);
+            }
+            parsed = Some(result);
+        }
+        parsed.unwrap()
+    }
+
+    #[test]
+    fn content_length() {
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nServer: test\r\n\r\nhello";
+        let (response, body) = parse(&get_request(), data);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body_size, 5);
+        assert_eq!(response.bytes_received, data.len());
+        assert!(!response.connection_close);
+        assert_eq!(body, b"hello");
+    }
+
+    #[test]
+    fn content_length_next_response() {
+        // the next response on the connection isn't part of this one
+        let mut parser = HttpResponseParser::new(&get_request());
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1 500 Oops\r\n";
+        let response = parser.feed(data).unwrap().unwrap();
+        assert_eq!(response.body_size, 2);
+        assert_eq!(parser.take_body(), b"ok");
+    }
+
+    #[test]
+    fn chunked() {
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip, chunked\r\n\r\n\
+            5;name=value\r\nhello\r\n\
+            7 ; ext\r\n, world\r\n\
+            0\r\nExpires: never\r\nX-Trailer: 1\r\n\r\n";
+        let (response, body) = parse(&get_request(), data);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body_size, 12);
+        assert_eq!(response.bytes_received, data.len());
+        assert_eq!(body, b"hello, world");
+    }
+
+    #[test]
+    fn chunked_errors() {
+        let mut parser = HttpResponseParser::new(&get_request());
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert!(parser
+            .feed(data)
+            .unwrap_err()
+            .contains("Invalid chunk size"));
+
+        let mut parser = HttpResponseParser::new(&get_request());
+        let data = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nabc\r\n";
+        assert!(parser.feed(data).unwrap_err().contains("Missing CRLF"));
+    }
+
+    #[test]
+    fn continue_then_final_response() {
+        let data = b"HTTP/1.1 100 Continue\r\n\r\n\
+            HTTP/1.1 201 Created\r\nContent-Length: 3\r\n\r\nnew";
+        let (response, body) = parse(&get_request(), data);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.bytes_received, data.len());
+        assert_eq!(body, b"new");
+    }
+
+    #[test]
+    fn no_body() {
+        // the length is the one of the body a GET request would get
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        let (response, body) = parse(&head_request(), data);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body_size, 0);
+        assert!(body.is_empty());
+
+        for status in ["204 No Content", "304 Not Modified"] {
+            let data = format!(
+                "HTTP/1.1 {}\r\nTransfer-Encoding: chunked\r\nContent-Length: 10\r\n\r\n",
+                status
+            );
+            let (response, body) = parse(&get_request(), data.as_bytes());
+            assert_eq!(response.status.to_string(), status[..3]);
+            assert_eq!(response.body_size, 0);
+            assert!(body.is_empty());
+        }
+    }
+
+    #[test]
+    fn body_until_close() {
+        let data = b"HTTP/1.1 200 OK\r\nServer: test\r\n\r\nsome body";
+        for size in [1, 4, data.len()] {
+            let mut parser = HttpResponseParser::new(&get_request());
+            assert_eq!(feed_split(&mut parser, data, size), Ok(None));
+
+            // the empty read of the closed connection ends the body
+            let response = parser.feed(b"").unwrap().unwrap();
+            assert_eq!(response.status, 200);
+            assert_eq!(response.body_size, 9);
+            assert!(response.connection_close);
+            assert_eq!(parser.take_body(), b"some body");
+        }
+    }
+
+    #[test]
+    fn closed_before_the_end() {
+        let responses: [&[u8]; 3] = [
+            b"HTTP/1.1 200 OK\r\nContent-Le",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort",
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel",
+        ];
+        for data in responses {
+            let mut parser = HttpResponseParser::new(&get_request());
+            assert_eq!(parser.feed(data), Ok(None));
+            let e = parser.feed(b"").unwrap_err();
+            assert!(e.contains("Connection closed"), "{}", e);
+        }
+    }
+
+    #[test]
+    fn connection_close() {
+        let data = b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n";
+        let (response, _) = parse(&get_request(), data);
+        assert!(response.connection_close);
+
+        // HTTP/1.0 closes the connection unless asked to keep it alive
+        let data = b"HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n";
+        let (response, _) = parse(&get_request(), data);
+        assert!(response.connection_close);
+        let data = b"HTTP/1.0 200 OK\r\nConnection: Keep-Alive\r\nContent-Length: 0\r\n\r\n";
+        let (response, _) = parse(&get_request(), data);
+        assert!(!response.connection_close);
+    }
+
+    #[test]
+    fn oversized_head() {
+        let mut parser = HttpResponseParser::new(&get_request());
+        assert_eq!(parser.feed(b"HTTP/1.1 200 OK\r\n"), Ok(None));
+
+        let header = format!("X-Padding: {}\r\n", "a".repeat(1000));
+        let mut result = Ok(None);
+        for _ in 0..HTTP_MAX_HEAD_SIZE / header.len() + 1 {
+            result = parser.feed(header.as_bytes());
+            if result.is_err() {
                 break;
             }
-            thread::sleep(std::time::Duration::from_millis(
-                config.read().unwrap().ping_interval,
-            ));
+        }
+        assert_eq!(result, Err("Response head too large".to_string()));
+    }
+
+    #[test]
+    fn invalid_head() {
+        let responses: [&[u8]; 3] = [
+            b"SSH-2.0-OpenSSH\r\n\r\n",
+            b"HTTP/1.1 OK\r\n\r\n",
+            b"HTTP/1.1 200 OK\r\nContent-Length: ten\r\n\r\n",
+        ];
+        for data in responses {
+            let mut parser = HttpResponseParser::new(&get_request());
+            assert!(parser.feed(data).is_err());
         }
     }
 }
@@ -1,120 +1,647 @@
-use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerType};
-use log::{debug, error};
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerResult};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{retry_ping, MPingerStopSignal, MessageSender, Runner};
+use log::debug;
 use rand::random;
 use socket2::{Domain, Protocol, Socket, Type};
-use std::mem::MaybeUninit;
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, Once};
+use std::thread;
 use std::time::{Duration, Instant};
 use time::OffsetDateTime;
 
-const ICMP_SIZE: usize = 64;
+// large enough for the reply to any payload size, which is echoed back
+const ICMP_BUFFER_SIZE: usize = 65536;
 
-pub struct MPingerICMP();
+// how often the engine threads check if the engine or one of its destinations was stopped
+const ENGINE_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-impl MPingerICMP {
-    pub fn start(
+// the engine thread sends for every destination, a full send buffer mustn't hold it up
+const ICMP_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_TIME_EXCEEDED: u8 = 11;
+const ICMPV6_DEST_UNREACHABLE: u8 = 1;
+const ICMPV6_TIME_EXCEEDED: u8 = 3;
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_ICMPV6: u8 = 58;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum IcmpSocketType {
+    // unprivileged "ping" socket, allowed by net.ipv4.ping_group_range
+    Dgram,
+    // raw socket, requires root or CAP_NET_RAW
+    Raw,
+}
+
+// try an unprivileged datagram socket first and fall back to a raw one
+fn open_icmp_socket(is_ipv6: bool) -> std::io::Result<(Socket, IcmpSocketType)> {
+    let (domain, protocol) = if is_ipv6 {
+        (Domain::IPV6, Protocol::ICMPV6)
+    } else {
+        (Domain::IPV4, Protocol::ICMPV4)
+    };
+
+    match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => return Ok((socket, IcmpSocketType::Dgram)),
+        Err(e) => debug!("Error creating datagram ICMP socket, trying raw: {}", e),
+    }
+
+    Socket::new(domain, Type::RAW, Some(protocol)).map(|socket| (socket, IcmpSocketType::Raw))
+}
+
+struct IcmpSocket {
+    // ICMP sockets only need send_to / recv_from, which UdpSocket provides for any datagram socket
+    socket: UdpSocket,
+    socket_type: IcmpSocketType,
+    is_ipv6: bool,
+}
+
+// called by a receiver thread with the reply arrival time and the ping result
+type IcmpReplyHandler = Box<dyn FnOnce(Instant, MPingerResult) + Send>;
+
+struct IcmpPending {
+    // destination of the Echo Request
+    ip: IpAddr,
+    on_reply: IcmpReplyHandler,
+}
+
+struct IcmpEngineShared {
+    running: AtomicBool,
+    // identifier used by raw sockets, datagram sockets get one assigned by the kernel
+    identifier: u16,
+    sequence: AtomicU16,
+    // Echo Requests waiting for a reply, by sequence number
+    pending: Mutex<HashMap<u16, IcmpPending>>,
+}
+
+// Echo Request of the current ping of a destination
+struct IcmpRequest {
+    sequence: u16,
+    // a late reply to an earlier attempt isn't taken for this one
+    attempt: u64,
+    send_time: OffsetDateTime,
+    start_time: Instant,
+    deadline: Instant,
+    // set by the receiver thread
+    reply: Option<(Instant, MPingerResult)>,
+}
+
+// Destination pinged by the engine thread
+struct IcmpDestination {
+    config: MPingerConfigShared,
+    dest: MPingDestination,
+    tx: Box<dyn MessageSender>,
+    count: usize,
+    stop_signal: Arc<MPingerStopSignal>,
+    done: Box<dyn FnOnce() + Send>,
+    ping_nr: usize,
+    retries: usize,
+    attempts: u64,
+    next_ping: Instant,
+    request: Option<IcmpRequest>,
+}
+
+impl IcmpDestination {
+    // sends the next Echo Request once it's time and returns the result of the current ping
+    // once its reply arrived or it timed out, wake_time is lowered to when to poll again
+    fn poll(
+        &mut self,
+        engine: &MPingerICMPEngine,
+        id: u64,
+        now: Instant,
+        wake_time: &mut Instant,
+    ) -> Option<MPingerMessage> {
+        let message = match self.request.take() {
+            Some(request) => {
+                let (rtt, result) = match request.reply {
+                    Some((recv_time, MPingerResult::Success)) => (
+                        recv_time.duration_since(request.start_time),
+                        MPingerResult::Success,
+                    ),
+                    Some((_, result)) => {
+                        debug!(
+                            "ICMP error received for {:?}: {}",
+                            self.dest.address, result
+                        );
+                        (Duration::ZERO, result)
+                    }
+                    None if now >= request.deadline => {
+                        engine.cancel(request.sequence);
+                        debug!("No ICMP Echo Reply received for {:?}", self.dest.address);
+                        (Duration::ZERO, MPingerResult::Timeout)
+                    }
+                    None => {
+                        *wake_time = (*wake_time).min(request.deadline);
+                        self.request = Some(request);
+                        return None;
+                    }
+                };
+
+                let message = MPingerMessage::new_ping(
+                    &self.dest,
+                    self.ping_nr,
+                    request.send_time,
+                    rtt,
+                    result,
+                );
+                self.report(message, now)
+            }
+            None if self.is_done() || self.next_ping > now => None,
+            None => self.send_request(engine, id, now),
+        };
+
+        if let Some(request) = self.request.as_ref() {
+            *wake_time = (*wake_time).min(request.deadline);
+        } else if !self.is_done() {
+            *wake_time = (*wake_time).min(self.next_ping);
+        }
+
+        message
+    }
+
+    fn send_request(
+        &mut self,
+        engine: &MPingerICMPEngine,
+        id: u64,
+        now: Instant,
+    ) -> Option<MPingerMessage> {
+        self.attempts += 1;
+        let attempt = self.attempts;
+        let payload = icmp_payload(&self.dest);
+        // raw IPv6 sockets reject a port that doesn't match the protocol, ICMP has no ports anyway
+        let sock_addr = SocketAddr::new(self.dest.ip(), 0);
+
+        let scheduler = engine.scheduler.clone();
+        let on_reply: IcmpReplyHandler = Box::new(move |recv_time, result| {
+            scheduler.set_reply(id, attempt, recv_time, result);
+        });
+
+        let send_time = OffsetDateTime::now_utc();
+        let start_time = Instant::now();
+        // send Echo Request
+        match engine.send(sock_addr, &payload, on_reply) {
+            Ok(sequence) => {
+                self.request = Some(IcmpRequest {
+                    sequence,
+                    attempt,
+                    send_time,
+                    start_time,
+                    deadline: start_time + self.dest.timeout(&self.config),
+                    reply: None,
+                });
+                None
+            }
+            Err(e) => {
+                debug!("Error sending ICMP packet: {}", e);
+                let message = MPingerMessage::new_ping(
+                    &self.dest,
+                    self.ping_nr,
+                    send_time,
+                    Duration::ZERO,
+                    MPingerResult::SendError,
+                );
+                self.report(message, now)
+            }
+        }
+    }
+
+    // returns the message to send, unless the failed ping is sent again right away
+    fn report(&mut self, message: MPingerMessage, now: Instant) -> Option<MPingerMessage> {
+        // only the last attempt of a failed ping is reported
+        if retry_ping(
+            &self.config,
+            &self.dest,
+            &self.stop_signal,
+            &message,
+            &mut self.retries,
+        ) {
+            self.next_ping = now;
+            return None;
+        }
+
+        self.ping_nr += 1;
+        self.next_ping = now + self.dest.ping_interval(&self.config);
+        Some(message)
+    }
+
+    // all pings are done, or the destination was stopped between two of them
+    fn is_done(&self) -> bool {
+        self.request.is_none()
+            && ((self.count > 0 && self.ping_nr >= self.count) || self.stop_signal.is_stopped())
+    }
+}
+
+#[derive(Default)]
+struct IcmpSchedule {
+    destinations: HashMap<u64, IcmpDestination>,
+    next_id: u64,
+}
+
+// Destinations of the engine, the engine thread is woken up when one of their replies arrives
+#[derive(Default)]
+struct IcmpScheduler {
+    schedule: Mutex<IcmpSchedule>,
+    cvar: Condvar,
+}
+
+impl IcmpScheduler {
+    fn set_reply(&self, id: u64, attempt: u64, recv_time: Instant, result: MPingerResult) {
+        let mut schedule = self.schedule.lock().unwrap();
+        let request = schedule
+            .destinations
+            .get_mut(&id)
+            .and_then(|destination| destination.request.as_mut());
+        if let Some(request) = request.filter(|request| request.attempt == attempt) {
+            request.reply = Some((recv_time, result));
+            self.cvar.notify_all();
+        }
+    }
+}
+
+// Pings all the ICMP destinations from a single thread, with one socket per address family
+// whose receiver thread dispatches the Echo Replies
+pub struct MPingerICMPEngine {
+    shared: Arc<IcmpEngineShared>,
+    scheduler: Arc<IcmpScheduler>,
+    // the engine thread starts with the first destination
+    started: Once,
+    socket_v4: Mutex<Option<Arc<IcmpSocket>>>,
+    socket_v6: Mutex<Option<Arc<IcmpSocket>>>,
+}
+
+impl MPingerICMPEngine {
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(IcmpEngineShared {
+                running: AtomicBool::new(true),
+                identifier: random::<u16>(),
+                sequence: AtomicU16::new(0),
+                pending: Mutex::new(HashMap::new()),
+            }),
+            scheduler: Arc::new(IcmpScheduler::default()),
+            started: Once::new(),
+            socket_v4: Mutex::new(None),
+            socket_v6: Mutex::new(None),
+        }
+    }
+
+    // ping the destination count times (0 for infinite pings) until stop_signal is set,
+    // done is called once it's over
+    pub fn add(
+        self: &Arc<Self>,
         config: MPingerConfigShared,
-        dest: &MPingDestination,
-        tx: mpsc::Sender<MPingerMessage>,
+        dest: MPingDestination,
+        tx: Box<dyn MessageSender>,
         count: usize,
+        stop_signal: Arc<MPingerStopSignal>,
+        done: Box<dyn FnOnce() + Send>,
     ) {
-        let socket = match Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4)) {
-            Ok(socket) => socket,
-            Err(e) => {
-                error!("Error creating ICMP socket: {}", e);
-                return;
+        let mut schedule = self.scheduler.schedule.lock().unwrap();
+        // the engine thread is gone
+        if !self.shared.running.load(Ordering::Relaxed) {
+            drop(schedule);
+            done();
+            return;
+        }
+
+        let id = schedule.next_id;
+        schedule.next_id += 1;
+        schedule.destinations.insert(
+            id,
+            IcmpDestination {
+                config,
+                dest,
+                tx,
+                count,
+                stop_signal,
+                done,
+                ping_nr: 0,
+                retries: 0,
+                attempts: 0,
+                next_ping: Instant::now(),
+                request: None,
+            },
+        );
+        drop(schedule);
+        self.scheduler.cvar.notify_all();
+
+        self.started.call_once(|| {
+            let engine = self.clone();
+            thread::spawn(move || engine.schedule_loop());
+        });
+    }
+
+    fn schedule_loop(&self) {
+        let mut schedule = self.scheduler.schedule.lock().unwrap();
+        while self.shared.running.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            // stop signals are polled, replies wake the thread up earlier
+            let mut wake_time = now + ENGINE_POLL_INTERVAL;
+
+            for (id, destination) in schedule.destinations.iter_mut() {
+                if let Some(message) = destination.poll(self, *id, now, &mut wake_time) {
+                    destination.tx.send_message(message);
+                }
             }
+
+            let done_ids: Vec<u64> = schedule
+                .destinations
+                .iter()
+                .filter(|(_, destination)| destination.is_done())
+                .map(|(id, _)| *id)
+                .collect();
+            if done_ids.is_empty() {
+                let timeout = wake_time.saturating_duration_since(Instant::now());
+                schedule = self
+                    .scheduler
+                    .cvar
+                    .wait_timeout(schedule, timeout)
+                    .unwrap()
+                    .0;
+                continue;
+            }
+
+            let finished: Vec<IcmpDestination> = done_ids
+                .iter()
+                .filter_map(|id| schedule.destinations.remove(id))
+                .collect();
+            // done takes the lock of the session, which may be adding a destination
+            drop(schedule);
+            for destination in finished {
+                (destination.done)();
+            }
+            schedule = self.scheduler.schedule.lock().unwrap();
+        }
+
+        let finished: Vec<IcmpDestination> =
+            schedule.destinations.drain().map(|(_, d)| d).collect();
+        drop(schedule);
+        for destination in finished {
+            (destination.done)();
+        }
+    }
+
+    // open the socket for the address family on first use and start its receiver thread
+    fn get_socket(&self, is_ipv6: bool) -> std::io::Result<Arc<IcmpSocket>> {
+        let mut slot = if is_ipv6 {
+            self.socket_v6.lock().unwrap()
+        } else {
+            self.socket_v4.lock().unwrap()
         };
 
-        socket
-            .set_read_timeout(Some(std::time::Duration::from_millis(
-                config.read().unwrap().timeout,
-            )))
-            .unwrap();
-        socket
-            .set_write_timeout(Some(std::time::Duration::from_millis(
-                config.read().unwrap().timeout,
-            )))
-            .unwrap();
+        if let Some(socket) = slot.as_ref() {
+            return Ok(socket.clone());
+        }
+
+        let (socket, socket_type) = open_icmp_socket(is_ipv6)?;
+        let socket: UdpSocket = socket.into();
+        socket.set_read_timeout(Some(ENGINE_POLL_INTERVAL))?;
+        socket.set_write_timeout(Some(ICMP_WRITE_TIMEOUT))?;
+
+        let socket = Arc::new(IcmpSocket {
+            socket,
+            socket_type,
+            is_ipv6,
+        });
+
+        let shared = self.shared.clone();
+        let receiver_socket = socket.clone();
+        thread::spawn(move || {
+            MPingerICMPEngine::receive_loop(shared, receiver_socket);
+        });
+
+        *slot = Some(socket.clone());
+
+        Ok(socket)
+    }
+
+    // send Echo Request, on_reply is called with the reply unless it is cancelled first
+    fn send(
+        &self,
+        sock_addr: SocketAddr,
+        payload: &[u8],
+        on_reply: IcmpReplyHandler,
+    ) -> std::io::Result<u16> {
+        let socket = self.get_socket(sock_addr.is_ipv6())?;
+
+        let sequence = {
+            let mut pending = self.shared.pending.lock().unwrap();
+            // skip sequence numbers still waiting for a reply after a wrap around
+            let mut sequence = self.shared.sequence.fetch_add(1, Ordering::Relaxed);
+            while pending.contains_key(&sequence) {
+                sequence = self.shared.sequence.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.insert(
+                sequence,
+                IcmpPending {
+                    ip: sock_addr.ip(),
+                    on_reply,
+                },
+            );
+            sequence
+        };
+
+        let packet =
+            build_icmp_echo_request(socket.is_ipv6, self.shared.identifier, sequence, payload);
+        if let Err(e) = socket.socket.send_to(&packet, sock_addr) {
+            self.cancel(sequence);
+            return Err(e);
+        }
 
-        let mut i = 0;
-        loop {
-            // Build ICMP Echo Request
-            let identifier = random::<u16>();
-            let sequence: u16 = i as u16;
-            let packet = build_icmp_echo_request(identifier, sequence, b"");
-
-            let start_time = Instant::now();
-            // send Echo Request
-            match socket.send_to(&packet, &dest.sock_addr) {
-                Ok(_) => {}
+        Ok(sequence)
+    }
+
+    // forget about an Echo Request which timed out
+    fn cancel(&self, sequence: u16) {
+        self.shared.pending.lock().unwrap().remove(&sequence);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.scheduler
+            .schedule
+            .lock()
+            .unwrap()
+            .destinations
+            .is_empty()
+    }
+
+    // the engine threads exit shortly, the remaining destinations are done
+    pub fn stop(&self) {
+        self.shared.running.store(false, Ordering::Relaxed);
+        self.scheduler.cvar.notify_all();
+    }
+
+    fn receive_loop(shared: Arc<IcmpEngineShared>, socket: Arc<IcmpSocket>) {
+        let mut buffer = vec![0u8; ICMP_BUFFER_SIZE];
+
+        while shared.running.load(Ordering::Relaxed) {
+            // receive Echo Reply or ICMP error
+            let (recv_size, from) = match socket.socket.recv_from(&mut buffer) {
+                Ok(result) => result,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    continue;
+                }
                 Err(e) => {
-                    debug!("Error sending ICMP packet: {}", e);
-                    let _ = tx.send(MPingerMessage {
-                        destination_id: dest.id,
-                        ping_nr: i,
-                        runner_type: MPingerType::ICMPPing,
-                        start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                        duration: 0,
-                        is_error: true,
-                    });
-                    break;
+                    debug!("Error receiving ICMP packet: {}", e);
+                    continue;
                 }
-            }
+            };
+            let recv_time = Instant::now();
 
-            let mut buffer = [MaybeUninit::uninit(); ICMP_SIZE];
-            let mut found = false;
-            for _ in 0..config.read().unwrap().ping_retries {
-                // receive Echo Reply
-                let (recv_size, _) = match socket.recv_from(&mut buffer) {
-                    Ok((size, _)) => (size, true),
-                    Err(e) => {
-                        debug!("Error receiving ICMP packet: {}", e);
-                        let _ = tx.send(MPingerMessage {
-                            destination_id: dest.id,
-                            ping_nr: i,
-                            runner_type: MPingerType::ICMPPing,
-                            start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                            duration: 0,
-                            is_error: true,
-                        });
-                        break;
-                    }
-                };
-                let buffer: [u8; ICMP_SIZE] = unsafe { std::mem::transmute(buffer) };
-
-                if is_valid_icmp_echo_response(&buffer, recv_size, identifier, sequence) {
-                    let rtt = Instant::now().duration_since(start_time);
-                    let _ = tx.send(MPingerMessage {
-                        destination_id: dest.id,
-                        ping_nr: i,
-                        runner_type: MPingerType::ICMPPing,
-                        start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                        duration: rtt.as_millis() as u64,
-                        is_error: false,
-                    });
-                    found = true;
-                    break;
+            let reply = match parse_icmp_reply(
+                &buffer[..recv_size],
+                from.ip(),
+                socket.is_ipv6,
+                socket.socket_type,
+                shared.identifier,
+            ) {
+                Some(reply) => reply,
+                None => continue,
+            };
+
+            let entry = {
+                let mut pending = shared.pending.lock().unwrap();
+                match pending.get(&reply.sequence) {
+                    Some(entry) if entry.ip == reply.ip => pending.remove(&reply.sequence),
+                    _ => None,
                 }
+            };
+            // the handler takes the lock of the engine thread, which may be sending
+            if let Some(entry) = entry {
+                (entry.on_reply)(recv_time, reply.result);
             }
+        }
+    }
+}
 
-            if !found {
-                debug!("No ICMP Echo Reply received for {:?}", dest.address);
-                let _ = tx.send(MPingerMessage {
-                    destination_id: dest.id,
-                    ping_nr: i,
-                    runner_type: MPingerType::ICMPPing,
-                    start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                    duration: 0,
-                    is_error: true,
-                });
+#[derive(Default)]
+pub struct MPingerICMP {
+    // the destinations of all sessions share a single engine, created on first use
+    // and stopped once the last one is done
+    engine: Mutex<Option<Arc<MPingerICMPEngine>>>,
+}
+
+impl MPingerICMP {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // the destination is added while holding the engine, so that a session which is over
+    // can't stop it in between: an engine is only stopped once it has no destinations left
+    fn add(
+        &self,
+        config: MPingerConfigShared,
+        dest: MPingDestination,
+        tx: Box<dyn MessageSender>,
+        count: usize,
+        stop_signal: Arc<MPingerStopSignal>,
+        done: Box<dyn FnOnce() + Send>,
+    ) {
+        let mut engine = self.engine.lock().unwrap();
+        engine
+            .get_or_insert_with(|| Arc::new(MPingerICMPEngine::new()))
+            .add(config, dest, tx, count, stop_signal, done);
+    }
+}
+
+impl Runner for MPingerICMP {
+    fn name(&self) -> &str {
+        "ICMP ping"
+    }
+
+    // the engine thread pings the destination, this only waits for it to be done
+    fn start(
+        &self,
+        config: MPingerConfigShared,
+        dest: &MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: &MPingerStopSignal,
+    ) {
+        let engine_stop_signal = Arc::new(MPingerStopSignal::new());
+        let (done_tx, done_rx) = mpsc::channel();
+        self.add(
+            config,
+            dest.clone(),
+            Box::new(tx),
+            count,
+            engine_stop_signal.clone(),
+            Box::new(move || {
+                let _ = done_tx.send(());
+            }),
+        );
+
+        while let Err(mpsc::RecvTimeoutError::Timeout) = done_rx.recv_timeout(ENGINE_POLL_INTERVAL)
+        {
+            if stop_signal.is_stopped() {
+                engine_stop_signal.stop();
             }
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let engine_stop_signal = Arc::new(MPingerStopSignal::new());
+            let (done_tx, mut done_rx) = tokio::sync::oneshot::channel();
+            self.add(
+                config,
+                dest.clone(),
+                Box::new(tx),
+                count,
+                engine_stop_signal.clone(),
+                Box::new(move || {
+                    let _ = done_tx.send(());
+                }),
+            );
 
-            i += 1;
-            if count > 0 && i >= count {
-                break;
+            while tokio::time::timeout(ENGINE_POLL_INTERVAL, &mut done_rx)
+                .await
+                .is_err()
+            {
+                if stop_signal.is_stopped() {
+                    engine_stop_signal.stop();
+                }
             }
-            std::thread::sleep(Duration::from_millis(config.read().unwrap().ping_interval));
+        }))
+    }
+
+    // no thread per destination, they are all pinged by the engine thread
+    fn spawn(
+        self: Arc<Self>,
+        config: MPingerConfigShared,
+        dest: MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: Arc<MPingerStopSignal>,
+        done: Box<dyn FnOnce() + Send>,
+    ) {
+        self.add(config, dest, Box::new(tx), count, stop_signal, done);
+    }
+
+    // called when a session is over, other sessions may still have destinations on the engine
+    fn stop(&self) {
+        let mut engine = self.engine.lock().unwrap();
+        if engine.as_ref().is_some_and(|engine| engine.is_idle()) {
+            engine.take().unwrap().stop();
         }
     }
 }
@@ -140,11 +667,25 @@ fn calculate_checksum(data: &[u8]) -> u16 {
     !sum as u16
 }
 
-fn build_icmp_echo_request(identifier: u16, sequence: u16, payload: &[u8]) -> Vec<u8> {
+// Echo data, none unless the destination overrides the payload size
+fn icmp_payload(dest: &MPingDestination) -> Vec<u8> {
+    vec![0u8; dest.payload_size().unwrap_or(0)]
+}
+
+fn build_icmp_echo_request(
+    is_ipv6: bool,
+    identifier: u16,
+    sequence: u16,
+    payload: &[u8],
+) -> Vec<u8> {
     let mut packet = Vec::with_capacity(8 + payload.len());
 
     // ICMP Header
-    packet.push(8); // Type: Echo Request
+    if is_ipv6 {
+        packet.push(ICMPV6_ECHO_REQUEST); // Type: Echo Request
+    } else {
+        packet.push(ICMP_ECHO_REQUEST); // Type: Echo Request
+    }
     packet.push(0); // Code: 0
     packet.push(0); // Checksum placeholder (high byte)
     packet.push(0); // Checksum placeholder (low byte)
@@ -152,6 +693,11 @@ fn build_icmp_echo_request(identifier: u16, sequence: u16, payload: &[u8]) -> Ve
     packet.extend_from_slice(&sequence.to_be_bytes());
     packet.extend_from_slice(payload); // Payload
 
+    // ICMPv6 checksum covers the IPv6 pseudo-header, the kernel fills it in for us
+    if is_ipv6 {
+        return packet;
+    }
+
     // Calculate checksum over the entire packet
     let checksum = calculate_checksum(&packet);
     packet[2] = (checksum >> 8) as u8; // High byte
@@ -160,29 +706,179 @@ fn build_icmp_echo_request(identifier: u16, sequence: u16, payload: &[u8]) -> Ve
     packet
 }
 
-fn is_valid_icmp_echo_response(
-    buffer: &[u8; ICMP_SIZE],
-    recv_size: usize,
-    identifier: u16,
+// reply to one of our Echo Requests, either an Echo Reply or an ICMP error quoting the request
+struct IcmpReply {
     sequence: u16,
-) -> bool {
-    let ip_header_len = (&buffer[0] & 0x0F) as usize * 4;
-    let icmp_start = ip_header_len;
-
-    if recv_size > icmp_start + 7 {
-        let icmp_type = buffer[icmp_start];
-        let icmp_code = buffer[icmp_start + 1];
-        let recv_identifier = u16::from_be_bytes([buffer[icmp_start + 4], buffer[icmp_start + 5]]);
-        let recv_sequence = u16::from_be_bytes([buffer[icmp_start + 6], buffer[icmp_start + 7]]);
-
-        if icmp_type == 0
-            && icmp_code == 0
-            && recv_identifier == identifier
-            && recv_sequence == sequence
-        {
-            return true;
+    // destination of the Echo Request the reply is about
+    ip: IpAddr,
+    result: MPingerResult,
+}
+
+// datagram sockets only deliver Echo Replies, ICMP errors are only seen through raw sockets
+fn parse_icmp_reply(
+    buffer: &[u8],
+    from: IpAddr,
+    is_ipv6: bool,
+    socket_type: IcmpSocketType,
+    identifier: u16,
+) -> Option<IcmpReply> {
+    let recv_size = buffer.len();
+    // raw IPv4 sockets deliver the IP header, raw IPv6 and datagram sockets don't
+    let icmp_start = if !is_ipv6 && socket_type == IcmpSocketType::Raw {
+        (buffer[0] & 0x0F) as usize * 4
+    } else {
+        0
+    };
+    if recv_size < icmp_start + 8 {
+        return None;
+    }
+
+    let icmp_type = buffer[icmp_start];
+    let icmp_code = buffer[icmp_start + 1];
+    let recv_identifier = u16::from_be_bytes([buffer[icmp_start + 4], buffer[icmp_start + 5]]);
+    let recv_sequence = u16::from_be_bytes([buffer[icmp_start + 6], buffer[icmp_start + 7]]);
+
+    let reply_type = if is_ipv6 {
+        ICMPV6_ECHO_REPLY
+    } else {
+        ICMP_ECHO_REPLY
+    };
+    if icmp_type == reply_type
+        && icmp_code == 0
+        // datagram sockets get the identifier replaced by the kernel
+        && (socket_type == IcmpSocketType::Dgram || recv_identifier == identifier)
+    {
+        return Some(IcmpReply {
+            sequence: recv_sequence,
+            ip: from,
+            result: MPingerResult::Success,
+        });
+    }
+
+    if socket_type != IcmpSocketType::Raw {
+        return None;
+    }
+
+    let result = match (is_ipv6, icmp_type) {
+        (false, ICMP_DEST_UNREACHABLE) | (true, ICMPV6_DEST_UNREACHABLE) => {
+            MPingerResult::Unreachable(Some(from))
+        }
+        (false, ICMP_TIME_EXCEEDED) | (true, ICMPV6_TIME_EXCEEDED) => {
+            MPingerResult::TtlExceeded(Some(from))
+        }
+        _ => return None,
+    };
+
+    // the error quotes the IP header and the first 8 bytes of our Echo Request
+    let quoted_start = icmp_start + 8;
+    let (ip, request_start) = if is_ipv6 {
+        if recv_size < quoted_start + 48 || buffer[quoted_start + 6] != IPPROTO_ICMPV6 {
+            return None;
+        }
+        let mut octets = [0u8; 16];
+        octets.copy_from_slice(&buffer[quoted_start + 24..quoted_start + 40]);
+        (IpAddr::from(octets), quoted_start + 40)
+    } else {
+        if recv_size < quoted_start + 20 {
+            return None;
+        }
+        let header_size = (buffer[quoted_start] & 0x0F) as usize * 4;
+        if recv_size < quoted_start + header_size + 8 || buffer[quoted_start + 9] != IPPROTO_ICMP {
+            return None;
+        }
+        let mut octets = [0u8; 4];
+        octets.copy_from_slice(&buffer[quoted_start + 16..quoted_start + 20]);
+        (IpAddr::from(octets), quoted_start + header_size)
+    };
+
+    let request_type = if is_ipv6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMP_ECHO_REQUEST
+    };
+    let request_identifier =
+        u16::from_be_bytes([buffer[request_start + 4], buffer[request_start + 5]]);
+    if buffer[request_start] != request_type || request_identifier != identifier {
+        return None;
+    }
+
+    Some(IcmpReply {
+        sequence: u16::from_be_bytes([buffer[request_start + 6], buffer[request_start + 7]]),
+        ip,
+        result,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::{
+        MPinger, MPingerConfig, MPingerConfigOverrides, MPingerMessageKind, MPingerReader,
+        MPingerType,
+    };
+
+    fn count_pings(reader: MPingerReader) -> usize {
+        let mut pings = 0;
+        while let Ok(message) = reader.recv() {
+            if message.kind == MPingerMessageKind::Ping {
+                assert_eq!(message.result, MPingerResult::Success);
+                pings += 1;
+            }
+        }
+        pings
+    }
+
+    #[test]
+    fn sessions_share_the_engine() {
+        // ICMP sockets may not be allowed here
+        if open_icmp_socket(false).is_err() {
+            return;
         }
+
+        let mut pinger = MPinger::new(MPingerConfig {
+            ping_interval: 100,
+            ..Default::default()
+        });
+        pinger
+            .add_destination(MPingerType::ICMPPing, "127.0.0.1")
+            .unwrap();
+
+        let long_session = pinger.start(10);
+        let short_session = pinger.start(2);
+
+        // the short session is over first, the engine keeps pinging for the long one
+        assert_eq!(count_pings(short_session), 2);
+        assert_eq!(count_pings(long_session), 10);
+
+        // and a new engine is started for the next session
+        assert_eq!(count_pings(pinger.start(2)), 2);
     }
 
-    false
+    #[test]
+    fn large_payloads() {
+        if open_icmp_socket(false).is_err() {
+            return;
+        }
+
+        // destinations with different payload sizes and timeouts share the socket
+        let mut pinger = MPinger::new(MPingerConfig {
+            ping_interval: 50,
+            ..Default::default()
+        });
+        for (payload_size, timeout) in [(56, 500), (1400, 1000), (8000, 2000)] {
+            pinger
+                .add_destination_with_overrides(
+                    MPingerType::ICMPPing,
+                    "127.0.0.1",
+                    MPingerConfigOverrides {
+                        payload_size: Some(payload_size),
+                        timeout: Some(timeout),
+                        ..Default::default()
+                    },
+                )
+                .unwrap();
+        }
+
+        assert_eq!(count_pings(pinger.start(3)), 9);
+    }
 }
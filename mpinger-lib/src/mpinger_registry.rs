@@ -0,0 +1,507 @@
+use crate::mpinger::{
+    MPingDestination, MPingerConfigShared, MPingerMessage, MPingerMessageKind, MPingerResult,
+};
+use crate::mpinger_runner::Runner;
+use crate::mpinger_session::MPingerSession;
+use crate::utils;
+use log::debug;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// how often the resolver thread checks if the sessions are over
+const RESOLVER_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+// Destinations of an MPinger and its running sessions
+#[derive(Debug, Default)]
+pub(crate) struct MPingerRegistry {
+    last_id: usize,
+    destinations: Vec<(MPingDestination, Arc<dyn Runner>)>,
+    // running sessions, new and removed destinations are passed on to them
+    sessions: Vec<Weak<MPingerSession>>,
+    // the thread re-resolving hostnames runs as long as there are running sessions
+    resolver_running: bool,
+}
+
+impl MPingerRegistry {
+    pub fn next_id(&mut self) -> usize {
+        self.last_id += 1;
+        self.last_id
+    }
+
+    // running sessions start pinging the destination right away
+    pub fn add(&mut self, dest: MPingDestination, runner: Arc<dyn Runner>) {
+        for session in self.get_sessions() {
+            session.add_destination(dest.clone(), runner.clone());
+        }
+
+        self.destinations.push((dest, runner));
+    }
+
+    // remove the destination and the ones added for the other addresses of its hostname
+    pub fn remove(&mut self, id: usize) -> bool {
+        let ids: Vec<usize> = self
+            .destinations
+            .iter()
+            .filter(|(dest, _)| dest.id == id || dest.parent_id == Some(id))
+            .map(|(dest, _)| dest.id)
+            .collect();
+        if ids.is_empty() {
+            return false;
+        }
+
+        self.destinations
+            .retain(|(dest, _)| !ids.contains(&dest.id));
+
+        for session in self.get_sessions() {
+            for id in ids.iter() {
+                session.remove_destination(*id);
+            }
+        }
+
+        true
+    }
+
+    pub fn get(&self, id: usize) -> Option<MPingDestination> {
+        self.destinations
+            .iter()
+            .find(|(dest, _)| dest.id == id)
+            .map(|(dest, _)| dest.clone())
+    }
+
+    fn get_sessions(&mut self) -> Vec<Arc<MPingerSession>> {
+        self.sessions.retain(|session| session.strong_count() > 0);
+        self.sessions.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    pub fn start_session(
+        registry: &Arc<Mutex<Self>>,
+        config: &MPingerConfigShared,
+        session: Arc<MPingerSession>,
+    ) {
+        let mut state = registry.lock().unwrap();
+
+        for (dest, runner) in state.destinations.iter() {
+            session.add_destination(dest.clone(), runner.clone());
+        }
+        session.close_if_idle();
+
+        state.sessions.push(Arc::downgrade(&session));
+
+        if state.resolver_running || config.read().unwrap().dns_ttl == 0 {
+            return;
+        }
+        state.resolver_running = true;
+
+        let registry = registry.clone();
+        let config = config.clone();
+        thread::spawn(move || {
+            MPingerRegistry::resolve_loop(registry, config);
+        });
+    }
+
+    fn resolve_loop(registry: Arc<Mutex<Self>>, config: MPingerConfigShared) {
+        let mut last_resolve = Instant::now();
+
+        loop {
+            thread::sleep(RESOLVER_CHECK_INTERVAL);
+
+            {
+                let mut state = registry.lock().unwrap();
+                let is_running = state
+                    .get_sessions()
+                    .iter()
+                    .any(|session| !session.is_stopped());
+                if !is_running {
+                    state.resolver_running = false;
+                    return;
+                }
+            }
+
+            let dns_ttl = config.read().unwrap().dns_ttl;
+            if dns_ttl == 0 || last_resolve.elapsed() < Duration::from_millis(dns_ttl) {
+                continue;
+            }
+            last_resolve = Instant::now();
+
+            MPingerRegistry::resolve(&registry, &config);
+        }
+    }
+
+    // sent for the destination and the ones of the other addresses of its hostname
+    fn report_dns_failure(registry: &Arc<Mutex<Self>>, dest: &MPingDestination) {
+        let mut state = registry.lock().unwrap();
+        let messages: Vec<MPingerMessage> = state
+            .destinations
+            .iter()
+            .filter(|(d, _)| d.id == dest.id || d.parent_id == Some(dest.id))
+            .map(|(d, _)| MPingerMessage {
+                result: MPingerResult::DnsFailure,
+                ..MPingerMessage::new_event(d, MPingerMessageKind::ResolveFailed)
+            })
+            .collect();
+
+        for session in state.get_sessions() {
+            for message in messages.iter() {
+                session.send_message(message.clone());
+            }
+        }
+    }
+
+    // resolve the hostnames again, follow address changes and update the destinations
+    // of the other addresses
+    fn resolve(registry: &Arc<Mutex<Self>>, config: &MPingerConfigShared) {
+        let resolve_all = config.read().unwrap().resolve_all;
+
+        let parents: Vec<(MPingDestination, Arc<dyn Runner>)> = registry
+            .lock()
+            .unwrap()
+            .destinations
+            .iter()
+            .filter(|(dest, _)| dest.parent_id.is_none() && dest.is_hostname())
+            .cloned()
+            .collect();
+
+        for (dest, runner) in parents {
+            // the registry isn't locked while resolving, it can take a while
+            let ips = match utils::resolve_host(&dest.host, dest.port, dest.ip_family) {
+                Ok(ips) => ips,
+                Err(e) => {
+                    debug!(
+                        "Error resolving {}, keeping {}: {}",
+                        dest.host,
+                        dest.ip(),
+                        e
+                    );
+                    MPingerRegistry::report_dns_failure(registry, &dest);
+                    continue;
+                }
+            };
+
+            let mut state = registry.lock().unwrap();
+            // removed while resolving
+            if state.get(dest.id).is_none() {
+                continue;
+            }
+            state.update_addresses(&dest, &runner, &ips, resolve_all);
+        }
+    }
+
+    // follow an address change of the destination and, with resolve_all, update the
+    // destinations of the other addresses of its hostname
+    fn update_addresses(
+        &mut self,
+        dest: &MPingDestination,
+        runner: &Arc<dyn Runner>,
+        ips: &[IpAddr],
+        resolve_all: bool,
+    ) {
+        let children: Vec<(usize, IpAddr)> = self
+            .destinations
+            .iter()
+            .filter(|(child, _)| child.parent_id == Some(dest.id))
+            .map(|(child, _)| (child.id, child.ip()))
+            .collect();
+        let changes = address_changes(dest.ip(), &children, ips, resolve_all);
+
+        if let Some(new) = changes.new_ip {
+            let old = dest.set_ip(new);
+            debug!("{} changed address from {} to {}", dest.host, old, new);
+
+            let message =
+                MPingerMessage::new_event(dest, MPingerMessageKind::AddressChanged { old, new });
+            for session in self.get_sessions() {
+                session.send_message(message.clone());
+            }
+        }
+
+        for id in changes.removed_children {
+            self.remove(id);
+        }
+        for ip in changes.added_ips {
+            let child = dest.new_child(self.next_id(), ip);
+            self.add(child, runner.clone());
+        }
+    }
+}
+
+// Changes to the destinations of a hostname once it is resolved again
+#[derive(Debug, Default, PartialEq, Eq)]
+struct AddressChanges {
+    // the current address of the destination is gone
+    new_ip: Option<IpAddr>,
+    // destinations of other addresses which are gone or became the one of the destination
+    removed_children: Vec<usize>,
+    // other addresses which have no destination yet
+    added_ips: Vec<IpAddr>,
+}
+
+// ip is the current address of the destination and children the ids and addresses of the
+// destinations of its other addresses, ips isn't empty
+fn address_changes(
+    ip: IpAddr,
+    children: &[(usize, IpAddr)],
+    ips: &[IpAddr],
+    resolve_all: bool,
+) -> AddressChanges {
+    let mut changes = AddressChanges::default();
+
+    // round robin DNS returns the addresses in a different order every time,
+    // only move to another address once the current one is gone
+    let ip = if ips.contains(&ip) {
+        ip
+    } else {
+        changes.new_ip = Some(ips[0]);
+        ips[0]
+    };
+
+    if !resolve_all {
+        return changes;
+    }
+
+    for (id, child_ip) in children {
+        if *child_ip == ip || !ips.contains(child_ip) {
+            changes.removed_children.push(*id);
+        }
+    }
+
+    for new_ip in ips {
+        if *new_ip == ip || children.iter().any(|(_, child_ip)| child_ip == new_ip) {
+            continue;
+        }
+        changes.added_ips.push(*new_ip);
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::{MPingerConfig, MPingerIpFamily, MPingerType};
+    use crate::mpinger_rnd::MPingerRnd;
+    use std::net::SocketAddr;
+    use std::sync::{mpsc, RwLock};
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn same_addresses() {
+        let children = [(2, ip("192.0.2.2"))];
+        // in another order
+        let ips = [ip("192.0.2.2"), ip("192.0.2.1")];
+        assert_eq!(
+            address_changes(ip("192.0.2.1"), &children, &ips, true),
+            AddressChanges::default()
+        );
+        assert_eq!(
+            address_changes(ip("192.0.2.1"), &[], &ips, false),
+            AddressChanges::default()
+        );
+    }
+
+    #[test]
+    fn address_changed() {
+        let ips = [ip("192.0.2.5"), ip("192.0.2.6")];
+        assert_eq!(
+            address_changes(ip("192.0.2.1"), &[], &ips, false),
+            AddressChanges {
+                new_ip: Some(ip("192.0.2.5")),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn other_addresses() {
+        let children = [(2, ip("192.0.2.2")), (3, ip("192.0.2.3"))];
+        let ips = [ip("192.0.2.3"), ip("192.0.2.1"), ip("192.0.2.4")];
+        assert_eq!(
+            address_changes(ip("192.0.2.1"), &children, &ips, true),
+            AddressChanges {
+                new_ip: None,
+                removed_children: vec![2],
+                added_ips: vec![ip("192.0.2.4")],
+            }
+        );
+    }
+
+    #[test]
+    fn address_of_a_child_taken_over() {
+        // the destination moves to the address of a child, which is removed
+        let children = [(2, ip("192.0.2.2")), (3, ip("192.0.2.3"))];
+        let ips = [ip("192.0.2.2"), ip("192.0.2.3")];
+        assert_eq!(
+            address_changes(ip("192.0.2.1"), &children, &ips, true),
+            AddressChanges {
+                new_ip: Some(ip("192.0.2.2")),
+                removed_children: vec![2],
+                added_ips: vec![],
+            }
+        );
+
+        // the children aren't touched without resolve_all
+        assert_eq!(
+            address_changes(ip("192.0.2.1"), &children, &ips, false),
+            AddressChanges {
+                new_ip: Some(ip("192.0.2.2")),
+                ..Default::default()
+            }
+        );
+    }
+
+    // a registry with a running session and a hostname destination with a child
+    struct TestRegistry {
+        registry: Arc<Mutex<MPingerRegistry>>,
+        session: Arc<MPingerSession>,
+        rx: mpsc::Receiver<MPingerMessage>,
+        dest: MPingDestination,
+        runner: Arc<dyn Runner>,
+    }
+
+    fn new_registry() -> TestRegistry {
+        let config = Arc::new(RwLock::new(MPingerConfig {
+            // no resolver thread
+            dns_ttl: 0,
+            ..Default::default()
+        }));
+        let runner: Arc<dyn Runner> = Arc::new(MPingerRnd());
+        let registry = Arc::new(Mutex::new(MPingerRegistry::default()));
+
+        let dest = {
+            let mut state = registry.lock().unwrap();
+            let dest = MPingDestination::new(
+                state.next_id(),
+                "example.test:80",
+                "example.test",
+                SocketAddr::new(ip("192.0.2.1"), 80),
+                MPingerIpFamily::Any,
+                MPingerType::Rnd,
+            );
+            let child = dest.new_child(state.next_id(), ip("192.0.2.2"));
+            state.add(dest.clone(), runner.clone());
+            state.add(child, runner.clone());
+            dest
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let session = MPingerSession::new_threads(config.clone(), 0, tx);
+        MPingerRegistry::start_session(&registry, &config, session.clone());
+
+        TestRegistry {
+            registry,
+            session,
+            rx,
+            dest,
+            runner,
+        }
+    }
+
+    // the next message which isn't a ping
+    fn next_event(rx: &mpsc::Receiver<MPingerMessage>) -> MPingerMessage {
+        loop {
+            let message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            if message.kind != MPingerMessageKind::Ping {
+                return message;
+            }
+        }
+    }
+
+    fn addresses(registry: &Arc<Mutex<MPingerRegistry>>) -> Vec<(usize, Option<usize>, IpAddr)> {
+        registry
+            .lock()
+            .unwrap()
+            .destinations
+            .iter()
+            .map(|(dest, _)| (dest.id, dest.parent_id, dest.ip()))
+            .collect()
+    }
+
+    #[test]
+    fn update_addresses() {
+        let TestRegistry {
+            registry,
+            session,
+            rx,
+            dest,
+            runner,
+        } = new_registry();
+        for id in [1, 2] {
+            let message = next_event(&rx);
+            assert_eq!(message.kind, MPingerMessageKind::DestinationStarted);
+            assert_eq!(message.destination_id, id);
+        }
+
+        // the destination moves to another address, the child of 192.0.2.2 is replaced
+        let ips = [ip("192.0.2.3"), ip("192.0.2.4")];
+        registry
+            .lock()
+            .unwrap()
+            .update_addresses(&dest, &runner, &ips, true);
+
+        let message = next_event(&rx);
+        assert_eq!(message.destination_id, 1);
+        assert_eq!(
+            message.kind,
+            MPingerMessageKind::AddressChanged {
+                old: ip("192.0.2.1"),
+                new: ip("192.0.2.3")
+            }
+        );
+        assert_eq!(dest.ip(), ip("192.0.2.3"));
+
+        let mut events: Vec<(usize, MPingerMessageKind)> = (0..2)
+            .map(|_| next_event(&rx))
+            .map(|message| (message.destination_id, message.kind))
+            .collect();
+        events.sort_by_key(|(id, _)| *id);
+        assert_eq!(
+            events,
+            [
+                (2, MPingerMessageKind::DestinationStopped),
+                (3, MPingerMessageKind::DestinationStarted)
+            ]
+        );
+        assert_eq!(
+            addresses(&registry),
+            [(1, None, ip("192.0.2.3")), (3, Some(1), ip("192.0.2.4"))]
+        );
+
+        session.stop();
+    }
+
+    #[test]
+    fn dns_failure() {
+        let TestRegistry {
+            registry,
+            session,
+            rx,
+            dest,
+            ..
+        } = new_registry();
+        for _ in 0..2 {
+            assert_eq!(next_event(&rx).kind, MPingerMessageKind::DestinationStarted);
+        }
+
+        // sent for the destination and its child, which keep their addresses
+        MPingerRegistry::report_dns_failure(&registry, &dest);
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let message = next_event(&rx);
+            assert_eq!(message.kind, MPingerMessageKind::ResolveFailed);
+            assert_eq!(message.result, MPingerResult::DnsFailure);
+            ids.push(message.destination_id);
+        }
+        ids.sort();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(
+            addresses(&registry),
+            [(1, None, ip("192.0.2.1")), (2, Some(1), ip("192.0.2.2"))]
+        );
+
+        session.stop();
+    }
+}
@@ -1,38 +1,61 @@
-use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerType};
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerResult};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{MPingerStopSignal, PingLoop, Runner};
 use rand::prelude::*;
 use std::sync::mpsc;
-use std::thread;
+use std::time::Duration;
 use time::OffsetDateTime;
 
 pub struct MPingerRnd();
 
-impl MPingerRnd {
-    pub fn start(
+impl Runner for MPingerRnd {
+    fn name(&self) -> &str {
+        "Random"
+    }
+
+    fn start(
+        &self,
         config: MPingerConfigShared,
         dest: &MPingDestination,
         tx: mpsc::Sender<MPingerMessage>,
         count: usize,
+        stop_signal: &MPingerStopSignal,
     ) {
         let mut rng = ::rand::rngs::StdRng::from_os_rng();
 
-        let mut i = 0;
-        loop {
-            let _ = tx.send(MPingerMessage {
-                destination_id: dest.id,
-                ping_nr: i,
-                runner_type: MPingerType::Rnd,
-                start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                duration: rng.random_range(0..=300),
-                is_error: false,
-            });
+        let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+        while let Some(i) = pings.next_ping() {
+            pings.report(rnd_ping(dest, i, &mut rng), &tx);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let mut rng = ::rand::rngs::StdRng::from_os_rng();
 
-            i += 1;
-            if count > 0 && i >= count {
-                break;
+            let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+            while let Some(i) = pings.next_ping_async().await {
+                pings.report(rnd_ping(dest, i, &mut rng), &tx);
             }
-            thread::sleep(std::time::Duration::from_millis(
-                config.read().unwrap().ping_interval,
-            ));
-        }
+        }))
     }
 }
+
+fn rnd_ping(dest: &MPingDestination, ping_nr: usize, rng: &mut StdRng) -> MPingerMessage {
+    MPingerMessage::new_ping(
+        dest,
+        ping_nr,
+        OffsetDateTime::now_utc(),
+        Duration::from_millis(rng.random_range(0..=300)),
+        MPingerResult::Success,
+    )
+}
@@ -0,0 +1,273 @@
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage};
+use log::debug;
+#[cfg(feature = "tokio")]
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Pings of a destination run by a tokio session, see Runner::start_async
+#[cfg(feature = "tokio")]
+pub type RunnerFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+// Ping method, implement it to add custom probe types with MPinger::add_destination_with_runner
+pub trait Runner: Send + Sync {
+    // human readable name, e.g. "ICMP ping"
+    fn name(&self) -> &str;
+
+    // port used when the address doesn't specify one, None to use MPingerConfig::default_port
+    fn default_port(&self) -> Option<u16> {
+        None
+    }
+
+    // ping the destination count times (0 for infinite pings) and send the results to tx,
+    // called from a dedicated thread per destination, returns when all pings are done
+    // or right after the current ping once stop_signal is set
+    fn start(
+        &self,
+        config: MPingerConfigShared,
+        dest: &MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: &MPingerStopSignal,
+    );
+
+    // start pinging the destination in the background and call done once it's over,
+    // runs start on a dedicated thread unless the runner shares threads between destinations
+    fn spawn(
+        self: Arc<Self>,
+        config: MPingerConfigShared,
+        dest: MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: Arc<MPingerStopSignal>,
+        done: Box<dyn FnOnce() + Send>,
+    ) where
+        Self: 'static,
+    {
+        thread::spawn(move || {
+            self.start(config, &dest, tx, count, &stop_signal);
+            done();
+        });
+    }
+
+    // async counterpart of start, run by the sessions of MPinger::start_async,
+    // runners without one have start run on the blocking thread pool
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        let _ = (config, dest, tx, count, stop_signal);
+        None
+    }
+
+    // called once all destinations using this runner are done, releases shared resources
+    fn stop(&self) {}
+}
+
+impl std::fmt::Debug for dyn Runner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Runner")
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+// Channel of the session the messages of a destination are sent to
+pub(crate) trait MessageSender: Send {
+    fn send_message(&self, message: MPingerMessage);
+}
+
+impl MessageSender for mpsc::Sender<MPingerMessage> {
+    fn send_message(&self, message: MPingerMessage) {
+        let result = self.send(message);
+        if result.is_err() {
+            debug!("Error sending message: {:?}", result);
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl MessageSender for tokio::sync::mpsc::UnboundedSender<MPingerMessage> {
+    fn send_message(&self, message: MPingerMessage) {
+        let result = self.send(message);
+        if result.is_err() {
+            debug!("Error sending message: {:?}", result);
+        }
+    }
+}
+
+// Ping loop of the built-in runners, the same for threads and tokio sessions:
+//   while let Some(ping_nr) = pings.next_ping() {
+//       pings.report(ping(ping_nr), &tx);
+//   }
+pub(crate) struct PingLoop<'a> {
+    config: &'a MPingerConfigShared,
+    dest: &'a MPingDestination,
+    stop_signal: &'a MPingerStopSignal,
+    // 0 for infinite pings
+    count: usize,
+    ping_nr: usize,
+    retries: usize,
+    // wait before the next ping, none before the first one and retries
+    delay: Option<Duration>,
+    done: bool,
+}
+
+impl<'a> PingLoop<'a> {
+    pub fn new(
+        config: &'a MPingerConfigShared,
+        dest: &'a MPingDestination,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Self {
+        Self {
+            config,
+            dest,
+            stop_signal,
+            count,
+            ping_nr: 0,
+            retries: 0,
+            delay: None,
+            done: false,
+        }
+    }
+
+    // waits until the next ping is due and returns its number, None once all pings are done
+    // or the session was stopped in the meantime
+    pub fn next_ping(&mut self) -> Option<usize> {
+        if let Some(delay) = self.delay.take() {
+            self.done = self.done || self.stop_signal.wait_timeout(delay);
+        }
+
+        (!self.done).then_some(self.ping_nr)
+    }
+
+    // async counterpart of next_ping
+    #[cfg(feature = "tokio")]
+    pub async fn next_ping_async(&mut self) -> Option<usize> {
+        if let Some(delay) = self.delay.take() {
+            self.done = self.done || self.stop_signal.sleep(delay).await;
+        }
+
+        (!self.done).then_some(self.ping_nr)
+    }
+
+    // sends the result of the ping, the next one follows after the ping interval
+    pub fn report(&mut self, message: MPingerMessage, tx: &impl MessageSender) {
+        let delay = self.dest.ping_interval(self.config);
+        self.report_with_delay(message, tx, delay);
+    }
+
+    pub fn report_with_delay(
+        &mut self,
+        message: MPingerMessage,
+        tx: &impl MessageSender,
+        delay: Duration,
+    ) {
+        // only the last attempt of a failed ping is reported
+        if retry_ping(
+            self.config,
+            self.dest,
+            self.stop_signal,
+            &message,
+            &mut self.retries,
+        ) {
+            return;
+        }
+        tx.send_message(message);
+
+        self.ping_nr += 1;
+        if self.count > 0 && self.ping_nr >= self.count {
+            self.done = true;
+        } else {
+            self.delay = Some(delay);
+        }
+    }
+
+    // the destination can't be pinged, e.g. its runner couldn't be configured
+    pub fn abort(&mut self, message: MPingerMessage, tx: &impl MessageSender) {
+        tx.send_message(message);
+        self.done = true;
+    }
+}
+
+// true if the failed ping should be sent again right away instead of being reported,
+// retries counts the attempts of the current ping and is reset once it is reported
+pub(crate) fn retry_ping(
+    config: &MPingerConfigShared,
+    dest: &MPingDestination,
+    stop_signal: &MPingerStopSignal,
+    message: &MPingerMessage,
+    retries: &mut usize,
+) -> bool {
+    if message.is_error() && *retries < dest.ping_retries(config) && !stop_signal.is_stopped() {
+        *retries += 1;
+        debug!(
+            "Ping {} to {} failed with {}, retry {}",
+            message.ping_nr + 1,
+            dest.address,
+            message.result,
+            retries
+        );
+        return true;
+    }
+
+    *retries = 0;
+    false
+}
+
+// Set when a session is stopped, runners finish their current ping and return
+#[derive(Debug, Default)]
+pub struct MPingerStopSignal {
+    stopped: Mutex<bool>,
+    cvar: Condvar,
+    #[cfg(feature = "tokio")]
+    notify: tokio::sync::Notify,
+}
+
+impl MPingerStopSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.cvar.notify_all();
+        #[cfg(feature = "tokio")]
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    // sleep between pings, returns true as soon as the session is stopped
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        let (stopped, _) = self
+            .cvar
+            .wait_timeout_while(stopped, timeout, |stopped| !*stopped)
+            .unwrap();
+
+        *stopped
+    }
+
+    // async counterpart of wait_timeout
+    #[cfg(feature = "tokio")]
+    pub async fn sleep(&self, timeout: Duration) -> bool {
+        let notified = self.notify.notified();
+        if self.is_stopped() {
+            return true;
+        }
+
+        tokio::time::timeout(timeout, notified).await.is_ok() || self.is_stopped()
+    }
+}
@@ -0,0 +1,364 @@
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerMessageKind};
+use crate::mpinger_runner::{MPingerStopSignal, Runner};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+
+// where the runners of a session run and send their messages to
+enum SessionBackend {
+    Threads {
+        tx: mpsc::Sender<MPingerMessage>,
+    },
+    #[cfg(feature = "tokio")]
+    Tokio {
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        runtime: tokio::runtime::Handle,
+    },
+}
+
+struct SessionState {
+    // dropped once the session is over, which ends the reader
+    backend: Option<SessionBackend>,
+    stopped: bool,
+    // stop signal of every running destination, by destination id
+    destinations: HashMap<usize, Arc<MPingerStopSignal>>,
+    // runners to stop once the session is over
+    runners: Vec<Arc<dyn Runner>>,
+}
+
+// A started MPinger, destinations can be added and removed while it runs
+pub(crate) struct MPingerSession {
+    config: MPingerConfigShared,
+    count: usize,
+    state: Mutex<SessionState>,
+}
+
+impl MPingerSession {
+    fn new(config: MPingerConfigShared, count: usize, backend: SessionBackend) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            count,
+            state: Mutex::new(SessionState {
+                backend: Some(backend),
+                stopped: false,
+                destinations: HashMap::new(),
+                runners: Vec::new(),
+            }),
+        })
+    }
+
+    pub fn new_threads(
+        config: MPingerConfigShared,
+        count: usize,
+        tx: mpsc::Sender<MPingerMessage>,
+    ) -> Arc<Self> {
+        MPingerSession::new(config, count, SessionBackend::Threads { tx })
+    }
+
+    // must be called from a tokio runtime, which runs all the destinations of the session
+    #[cfg(feature = "tokio")]
+    pub fn new_tokio(
+        config: MPingerConfigShared,
+        count: usize,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+    ) -> Arc<Self> {
+        MPingerSession::new(
+            config,
+            count,
+            SessionBackend::Tokio {
+                tx,
+                runtime: tokio::runtime::Handle::current(),
+            },
+        )
+    }
+
+    // start pinging the destination, returns false if the session is already over
+    pub fn add_destination(
+        self: &Arc<Self>,
+        dest: MPingDestination,
+        runner: Arc<dyn Runner>,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped || state.destinations.contains_key(&dest.id) {
+            return false;
+        }
+
+        if state.backend.is_none() {
+            return false;
+        }
+        if !state.runners.iter().any(|r| Arc::ptr_eq(r, &runner)) {
+            state.runners.push(runner.clone());
+        }
+
+        let id = dest.id;
+        let stop_signal = Arc::new(MPingerStopSignal::new());
+        let config = self.config.clone();
+        let count = self.count;
+        let session = self.clone();
+        state.destinations.insert(id, stop_signal.clone());
+
+        match state.backend.as_ref() {
+            Some(SessionBackend::Threads { tx }) => {
+                let tx = tx.clone();
+                // the runner may call done right away, which takes the lock again
+                drop(state);
+
+                let _ = tx.send(MPingerMessage::new_event(
+                    &dest,
+                    MPingerMessageKind::DestinationStarted,
+                ));
+                let stopped_event =
+                    MPingerMessage::new_event(&dest, MPingerMessageKind::DestinationStopped);
+                let done_tx = tx.clone();
+                let done = Box::new(move || {
+                    let _ = done_tx.send(stopped_event);
+                    drop(done_tx);
+
+                    session.finish_destination(id);
+                });
+                runner.spawn(config, dest, tx, count, stop_signal, done);
+            }
+            #[cfg(feature = "tokio")]
+            Some(SessionBackend::Tokio { tx, runtime }) => {
+                let tx = tx.clone();
+
+                runtime.spawn(async move {
+                    let _ = tx.send(MPingerMessage::new_event(
+                        &dest,
+                        MPingerMessageKind::DestinationStarted,
+                    ));
+                    crate::mpinger_async::run_destination(
+                        runner,
+                        config,
+                        &dest,
+                        tx.clone(),
+                        count,
+                        stop_signal,
+                    )
+                    .await;
+                    let _ = tx.send(MPingerMessage::new_event(
+                        &dest,
+                        MPingerMessageKind::DestinationStopped,
+                    ));
+                    drop(tx);
+
+                    session.finish_destination(dest.id);
+                });
+            }
+            None => {}
+        }
+
+        true
+    }
+
+    // the destination finishes its current ping and stops
+    pub fn remove_destination(&self, id: usize) -> bool {
+        match self.state.lock().unwrap().destinations.get(&id) {
+            Some(stop_signal) => {
+                stop_signal.stop();
+                true
+            }
+            None => false,
+        }
+    }
+
+    // send a message about a running destination which doesn't come from its runner
+    pub fn send_message(&self, message: MPingerMessage) {
+        let state = self.state.lock().unwrap();
+        if !state.destinations.contains_key(&message.destination_id) {
+            return;
+        }
+
+        match state.backend.as_ref() {
+            Some(SessionBackend::Threads { tx }) => {
+                let _ = tx.send(message);
+            }
+            #[cfg(feature = "tokio")]
+            Some(SessionBackend::Tokio { tx, .. }) => {
+                let _ = tx.send(message);
+            }
+            None => {}
+        }
+    }
+
+    pub fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        for stop_signal in state.destinations.values() {
+            stop_signal.stop();
+        }
+        drop(state);
+
+        self.close_if_idle();
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.state.lock().unwrap().stopped
+    }
+
+    fn finish_destination(&self, id: usize) {
+        self.state.lock().unwrap().destinations.remove(&id);
+        self.close_if_idle();
+    }
+
+    // a session with no destinations left is over, unless it pings forever and can get new ones
+    pub fn close_if_idle(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.destinations.is_empty() || (self.count == 0 && !state.stopped) {
+            return;
+        }
+
+        state.stopped = true;
+        let backend = state.backend.take();
+        let runners: Vec<Arc<dyn Runner>> = state.runners.drain(..).collect();
+        drop(state);
+
+        debug!("Session is over, stopping {} runners", runners.len());
+        for runner in runners {
+            runner.stop();
+        }
+        drop(backend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mpinger::{
+        MPinger, MPingerConfig, MPingerHandle, MPingerMessage, MPingerMessageKind, MPingerType,
+    };
+    use std::time::Duration;
+
+    // the next message, None once the session is over
+    type NextMessage<'a> = Box<dyn FnMut() -> Option<MPingerMessage> + 'a>;
+
+    const MESSAGE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn new_pinger() -> MPinger {
+        MPinger::new(MPingerConfig {
+            ping_interval: 10,
+            ..Default::default()
+        })
+    }
+
+    // skips the pings of other destinations
+    fn wait_for(next: &mut NextMessage, id: usize, kind: MPingerMessageKind) {
+        loop {
+            let message = next().expect("session over");
+            if message.destination_id == id && message.kind == kind {
+                return;
+            }
+        }
+    }
+
+    // the first destination is added before the session starts, otherwise a session with
+    // a count is over right away
+    const FIRST: usize = 1;
+
+    // pings the first destination, adds another one to the running session and removes both
+    fn add_and_remove(pinger: &mut MPinger, next: &mut NextMessage) {
+        wait_for(next, FIRST, MPingerMessageKind::DestinationStarted);
+        wait_for(next, FIRST, MPingerMessageKind::Ping);
+
+        let second = pinger
+            .add_destination(MPingerType::Rnd, "192.0.2.2")
+            .unwrap();
+        wait_for(next, second, MPingerMessageKind::DestinationStarted);
+        wait_for(next, second, MPingerMessageKind::Ping);
+
+        assert!(pinger.remove_destination(FIRST));
+        assert!(!pinger.remove_destination(FIRST));
+        wait_for(next, FIRST, MPingerMessageKind::DestinationStopped);
+        // the other destination keeps pinging, the removed one is done
+        for _ in 0..3 {
+            let message = next().expect("session over");
+            assert_eq!(message.destination_id, second);
+            assert_eq!(message.kind, MPingerMessageKind::Ping);
+        }
+
+        assert!(pinger.remove_destination(second));
+        wait_for(next, second, MPingerMessageKind::DestinationStopped);
+    }
+
+    fn check_session(next: &mut NextMessage, handle: &MPingerHandle, pinger: &mut MPinger) {
+        add_and_remove(pinger, next);
+        // the reader ends with the last destination
+        while let Some(message) = next() {
+            assert_eq!(message.kind, MPingerMessageKind::Ping, "{:?}", message);
+        }
+        assert!(handle.is_stopped());
+    }
+
+    fn check_endless_session(next: &mut NextMessage, handle: &MPingerHandle, pinger: &mut MPinger) {
+        add_and_remove(pinger, next);
+        // a session pinging forever waits for new destinations
+        assert!(!handle.is_stopped());
+        let third = pinger
+            .add_destination(MPingerType::Rnd, "192.0.2.3")
+            .unwrap();
+        wait_for(next, third, MPingerMessageKind::DestinationStarted);
+        wait_for(next, third, MPingerMessageKind::Ping);
+
+        handle.stop();
+        wait_for(next, third, MPingerMessageKind::DestinationStopped);
+        assert!(next().is_none());
+    }
+
+    fn check_threads(count: usize, check: fn(&mut NextMessage, &MPingerHandle, &mut MPinger)) {
+        let mut pinger = new_pinger();
+        let first = pinger
+            .add_destination(MPingerType::Rnd, "192.0.2.1")
+            .unwrap();
+        assert_eq!(first, FIRST);
+        let reader = pinger.start(count);
+        let handle = reader.get_handle();
+        let mut next: NextMessage = Box::new(|| match reader.recv_timeout(MESSAGE_TIMEOUT) {
+            Ok(message) => Some(message),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => None,
+            Err(e) => panic!("{}", e),
+        });
+        check(&mut next, &handle, &mut pinger);
+    }
+
+    #[cfg(feature = "tokio")]
+    fn check_tokio(count: usize, check: fn(&mut NextMessage, &MPingerHandle, &mut MPinger)) {
+        use futures_core::Stream;
+        use std::pin::Pin;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let mut pinger = new_pinger();
+        let first = pinger
+            .add_destination(MPingerType::Rnd, "192.0.2.1")
+            .unwrap();
+        assert_eq!(first, FIRST);
+        let mut stream = pinger.start_async(count);
+        let handle = stream.get_handle();
+        let mut next: NextMessage = Box::new(|| {
+            runtime.block_on(async {
+                let message = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx));
+                tokio::time::timeout(MESSAGE_TIMEOUT, message)
+                    .await
+                    .expect("no message")
+            })
+        });
+        check(&mut next, &handle, &mut pinger);
+    }
+
+    #[test]
+    fn session_ends_with_the_last_destination() {
+        check_threads(1000, check_session);
+        #[cfg(feature = "tokio")]
+        check_tokio(1000, check_session);
+    }
+
+    #[test]
+    fn endless_session_waits_for_destinations() {
+        check_threads(0, check_endless_session);
+        #[cfg(feature = "tokio")]
+        check_tokio(0, check_endless_session);
+    }
+}
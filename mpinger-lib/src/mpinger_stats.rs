@@ -0,0 +1,258 @@
+use crate::mpinger::{MPingerDetails, MPingerMessage, MPingerMessageKind, MPingerResult};
+use crate::utils::Histogram;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// Statistics of all destinations, fed with the messages of a session
+#[derive(Debug, Default, Clone)]
+pub struct MPingerStats {
+    destinations: BTreeMap<usize, MPingerDestinationStats>,
+}
+
+impl MPingerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // only ping results are counted, other messages are ignored
+    pub fn add(&mut self, message: &MPingerMessage) {
+        if message.kind != MPingerMessageKind::Ping {
+            return;
+        }
+
+        self.destinations
+            .entry(message.destination_id)
+            .or_default()
+            .add(message);
+    }
+
+    pub fn get(&self, destination_id: usize) -> Option<&MPingerDestinationStats> {
+        self.destinations.get(&destination_id)
+    }
+
+    // ordered by destination id
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &MPingerDestinationStats)> {
+        self.destinations.iter().map(|(id, stats)| (*id, stats))
+    }
+}
+
+// Statistics of the pings of a destination
+#[derive(Debug, Default, Clone)]
+pub struct MPingerDestinationStats {
+    pub pings: usize,
+    // failed pings, including the lost ones
+    pub errors: usize,
+    // pings without any response
+    pub lost: usize,
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    // replies to UDP probes which didn't match them, see MPingerUdpDetails
+    pub late_replies: usize,
+    pub duplicate_replies: usize,
+    pub reordered_replies: usize,
+    // RTTs of successful pings in us, for percentiles
+    pub rtt_histogram: Histogram,
+    // RTTs of successful pings in us: count, mean and sum of squared differences from the mean
+    rtt_count: usize,
+    rtt_mean: f64,
+    rtt_m2: f64,
+    last_rtt: Option<f64>,
+    // interarrival jitter in us
+    jitter: f64,
+}
+
+impl MPingerDestinationStats {
+    pub fn add(&mut self, message: &MPingerMessage) {
+        self.pings += 1;
+
+        if let MPingerDetails::Udp(details) = &message.details {
+            self.late_replies += details.late_replies;
+            self.duplicate_replies += details.duplicate_replies;
+            self.reordered_replies += details.reordered_replies;
+        }
+
+        if message.is_error() {
+            self.errors += 1;
+            if message.result == MPingerResult::Timeout {
+                self.lost += 1;
+            }
+            return;
+        }
+
+        let rtt = message.rtt;
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt)));
+        self.max_rtt = Some(self.max_rtt.map_or(rtt, |max_rtt| max_rtt.max(rtt)));
+        self.rtt_histogram.add(rtt.as_micros() as u64);
+
+        // Welford's online algorithm
+        let rtt = rtt.as_secs_f64() * 1_000_000.0;
+        self.rtt_count += 1;
+        let delta = rtt - self.rtt_mean;
+        self.rtt_mean += delta / self.rtt_count as f64;
+        self.rtt_m2 += delta * (rtt - self.rtt_mean);
+
+        // RFC 3550 interarrival jitter, the RTT differences of consecutive replies smoothed by 1/16
+        if let Some(last_rtt) = self.last_rtt {
+            self.jitter += ((rtt - last_rtt).abs() - self.jitter) / 16.0;
+        }
+        self.last_rtt = Some(rtt);
+    }
+
+    // percentage of pings without any response
+    pub fn loss(&self) -> f64 {
+        match self.pings {
+            0 => 0.0,
+            pings => self.lost as f64 * 100.0 / pings as f64,
+        }
+    }
+
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        match self.rtt_count {
+            0 => None,
+            _ => Some(from_micros(self.rtt_mean)),
+        }
+    }
+
+    // population standard deviation of the RTTs
+    pub fn stddev(&self) -> Option<Duration> {
+        match self.rtt_count {
+            0 => None,
+            count => Some(from_micros((self.rtt_m2 / count as f64).sqrt())),
+        }
+    }
+
+    // RTT below which the given percentage of successful pings are, e.g. 99.9
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        self.rtt_histogram
+            .percentile(percentile)
+            .map(Duration::from_micros)
+    }
+
+    // None until two pings succeeded
+    pub fn jitter(&self) -> Option<Duration> {
+        match self.rtt_count {
+            0 | 1 => None,
+            _ => Some(from_micros(self.jitter)),
+        }
+    }
+}
+
+fn from_micros(micros: f64) -> Duration {
+    Duration::from_secs_f64(micros.max(0.0) / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::{MPingerType, MPingerUdpDetails};
+    use time::OffsetDateTime;
+
+    fn ping(destination_id: usize, rtt_ms: u64, result: MPingerResult) -> MPingerMessage {
+        MPingerMessage {
+            kind: MPingerMessageKind::Ping,
+            destination_id,
+            ping_nr: 0,
+            runner_type: MPingerType::Rnd,
+            send_time: OffsetDateTime::now_utc(),
+            rtt: Duration::from_millis(rtt_ms),
+            result,
+            details: MPingerDetails::None,
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let stats = MPingerDestinationStats::default();
+        assert_eq!(stats.loss(), 0.0);
+        assert_eq!(stats.avg_rtt(), None);
+        assert_eq!(stats.stddev(), None);
+        assert_eq!(stats.percentile(50.0), None);
+        assert_eq!(stats.jitter(), None);
+    }
+
+    #[test]
+    fn rtts() {
+        let mut stats = MPingerDestinationStats::default();
+        for rtt in [10, 20, 30] {
+            stats.add(&ping(1, rtt, MPingerResult::Success));
+        }
+
+        assert_eq!(stats.pings, 3);
+        assert_eq!(stats.errors, 0);
+        assert_eq!(stats.min_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(stats.max_rtt, Some(Duration::from_millis(30)));
+        assert_eq!(stats.avg_rtt(), Some(Duration::from_millis(20)));
+        // sqrt(200 / 3) ms
+        let stddev = stats.stddev().unwrap().as_secs_f64() * 1000.0;
+        assert!((stddev - 8.165).abs() < 0.001, "{}", stddev);
+        // within the 1/64 precision of the histogram
+        let p50 = stats.percentile(50.0).unwrap().as_micros() as f64;
+        assert!((p50 - 20_000.0).abs() <= 20_000.0 / 64.0, "{}", p50);
+        assert_eq!(stats.percentile(100.0), Some(Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn jitter() {
+        let mut stats = MPingerDestinationStats::default();
+        stats.add(&ping(1, 10, MPingerResult::Success));
+        assert_eq!(stats.jitter(), None);
+
+        // a 10 ms difference, smoothed by 1/16
+        stats.add(&ping(1, 20, MPingerResult::Success));
+        assert_eq!(stats.jitter(), Some(Duration::from_micros(625)));
+    }
+
+    #[test]
+    fn errors_and_loss() {
+        let mut stats = MPingerDestinationStats::default();
+        stats.add(&ping(1, 10, MPingerResult::Success));
+        stats.add(&ping(1, 0, MPingerResult::Timeout));
+        stats.add(&ping(1, 0, MPingerResult::ConnectionRefused));
+        stats.add(&ping(1, 5, MPingerResult::HttpStatus(500)));
+
+        assert_eq!(stats.pings, 4);
+        assert_eq!(stats.errors, 3);
+        // only pings without any response are lost
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.loss(), 25.0);
+        // failed pings have no RTT
+        assert_eq!(stats.max_rtt, Some(Duration::from_millis(10)));
+        assert_eq!(stats.rtt_histogram.count(), 1);
+    }
+
+    #[test]
+    fn udp_replies() {
+        let mut stats = MPingerDestinationStats::default();
+        let mut message = ping(1, 10, MPingerResult::Success);
+        message.details = MPingerDetails::Udp(MPingerUdpDetails {
+            late_replies: 2,
+            duplicate_replies: 1,
+            reordered_replies: 1,
+            ..Default::default()
+        });
+        stats.add(&message);
+        stats.add(&message);
+
+        assert_eq!(stats.late_replies, 4);
+        assert_eq!(stats.duplicate_replies, 2);
+        assert_eq!(stats.reordered_replies, 2);
+    }
+
+    #[test]
+    fn destinations() {
+        let mut stats = MPingerStats::new();
+        stats.add(&ping(2, 10, MPingerResult::Success));
+        stats.add(&ping(1, 10, MPingerResult::Success));
+        stats.add(&ping(2, 10, MPingerResult::Timeout));
+
+        // other messages are ignored
+        let mut event = ping(3, 0, MPingerResult::DnsFailure);
+        event.kind = MPingerMessageKind::ResolveFailed;
+        stats.add(&event);
+
+        let ids: Vec<usize> = stats.iter().map(|(id, _)| id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(stats.get(2).unwrap().pings, 2);
+        assert!(stats.get(3).is_none());
+    }
+}
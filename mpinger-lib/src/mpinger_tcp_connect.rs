@@ -1,78 +1,100 @@
-use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerType};
-use log::{debug, error};
-use socket2::{Domain, Protocol, Socket, Type};
+use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerResult};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{MPingerStopSignal, PingLoop, Runner};
+use log::debug;
+use socket2::{Protocol, SockAddr, Socket, Type};
 use std::sync::mpsc;
-use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
 
 pub struct MPingerTCPConnect();
 
-impl MPingerTCPConnect {
-    pub fn start(
+impl Runner for MPingerTCPConnect {
+    fn name(&self) -> &str {
+        "TCP Connect"
+    }
+
+    fn start(
+        &self,
         config: MPingerConfigShared,
         dest: &MPingDestination,
         tx: mpsc::Sender<MPingerMessage>,
         count: usize,
+        stop_signal: &MPingerStopSignal,
     ) {
-        let timeout = std::time::Duration::from_millis(config.read().unwrap().timeout);
+        let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+        while let Some(i) = pings.next_ping() {
+            let send_time = OffsetDateTime::now_utc();
+            let result = tcp_connect(&dest.sock_addr(), dest.timeout(&config));
+            pings.report(get_tcp_connect_message(dest, i, send_time, result), &tx);
+        }
+    }
 
-        let mut i = 0;
-        loop {
-            let socket = match Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)) {
-                Ok(socket) => socket,
-                Err(e) => {
-                    error!("Error creating socket: {:?}", e);
-                    continue;
-                }
-            };
-            socket
-                .set_read_timeout(Some(std::time::Duration::from_millis(
-                    config.read().unwrap().timeout,
-                )))
-                .unwrap();
-            socket
-                .set_write_timeout(Some(std::time::Duration::from_millis(
-                    config.read().unwrap().timeout,
-                )))
-                .unwrap();
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+            while let Some(i) = pings.next_ping_async().await {
+                let send_time = OffsetDateTime::now_utc();
+                let result = tcp_connect_async(dest.socket_addr(), dest.timeout(&config)).await;
+                pings.report(get_tcp_connect_message(dest, i, send_time, result), &tx);
+            }
+        }))
+    }
+}
 
-            let start_time = Instant::now();
-            let result = socket.connect_timeout(&dest.sock_addr, timeout);
-            let duration = start_time.elapsed().as_micros() as u32;
+// returns how long the connect took, the connection is closed right away
+fn tcp_connect(sock_addr: &SockAddr, timeout: Duration) -> std::io::Result<Duration> {
+    let socket = Socket::new(sock_addr.domain(), Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.set_write_timeout(Some(timeout))?;
 
-            let duration = match result {
-                Ok(_) => duration,
-                Err(e) => {
-                    debug!("Error connecting: {}", e);
-                    0
-                }
-            };
+    let start_time = Instant::now();
+    socket.connect_timeout(sock_addr, timeout)?;
+    let rtt = start_time.elapsed();
 
-            let result = tx.send(MPingerMessage {
-                destination_id: dest.id,
-                ping_nr: i,
-                runner_type: MPingerType::TCPConnect,
-                start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                duration: duration as u64,
-                is_error: false,
-            });
-            if result.is_err() {
-                debug!("Error sending message: {:?}", result);
-            }
+    let result = socket.shutdown(std::net::Shutdown::Both);
+    if result.is_err() {
+        debug!("Error shutting down socket: {:?}", result);
+    }
 
-            let result = socket.shutdown(std::net::Shutdown::Both);
-            if result.is_err() {
-                debug!("Error shutting down socket: {:?}", result);
-            }
+    Ok(rtt)
+}
 
-            i += 1;
-            if count > 0 && i >= count {
-                break;
-            }
-            thread::sleep(std::time::Duration::from_millis(
-                config.read().unwrap().ping_interval,
-            ));
+#[cfg(feature = "tokio")]
+async fn tcp_connect_async(
+    sock_addr: std::net::SocketAddr,
+    timeout: Duration,
+) -> std::io::Result<Duration> {
+    let start_time = Instant::now();
+    tokio::time::timeout(timeout, tokio::net::TcpStream::connect(sock_addr))
+        .await
+        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))?;
+
+    Ok(start_time.elapsed())
+}
+
+fn get_tcp_connect_message(
+    dest: &MPingDestination,
+    ping_nr: usize,
+    send_time: OffsetDateTime,
+    result: std::io::Result<Duration>,
+) -> MPingerMessage {
+    let (rtt, result) = match result {
+        Ok(rtt) => (rtt, MPingerResult::Success),
+        Err(e) => {
+            debug!("Error connecting: {}", e);
+            (Duration::ZERO, MPingerResult::from_io_error(&e))
         }
-    }
+    };
+
+    MPingerMessage::new_ping(dest, ping_nr, send_time, rtt, result)
 }
@@ -0,0 +1,683 @@
+use crate::mpinger::{
+    MPingDestination, MPingerConfigShared, MPingerDetails, MPingerMessage, MPingerResult,
+    MPingerTlsChainStatus, MPingerTlsDetails,
+};
+use crate::mpinger_http_keepalive::{connect, run_http, HttpConnection};
+#[cfg(feature = "tokio")]
+use crate::mpinger_http_keepalive::{connect_async, run_http_async};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{MPingerStopSignal, MessageSender, PingLoop, Runner};
+use log::{debug, error};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    CertificateError, ClientConfig, ClientConnection, CommonState, DigitallySignedStruct,
+    ProtocolVersion, RootCertStore, SignatureScheme, StreamOwned,
+};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::{mpsc, Arc, OnceLock};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWriteExt;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+// Measures the TCP connect and TLS handshake, a new connection for every ping
+pub struct MPingerTLS();
+
+impl Runner for MPingerTLS {
+    fn name(&self) -> &str {
+        "TLS handshake"
+    }
+
+    fn default_port(&self) -> Option<u16> {
+        Some(443)
+    }
+
+    fn start(
+        &self,
+        config: MPingerConfigShared,
+        dest: &MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: &MPingerStopSignal,
+    ) {
+        // the certificate is checked after the handshake, its details are reported either way
+        let Some(tls_config) = tls_runner_config(dest, &[], false, &tx) else {
+            return;
+        };
+
+        let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+        while let Some(i) = pings.next_ping() {
+            let send_time = OffsetDateTime::now_utc();
+            let result = tls_ping(&config, dest, &tls_config);
+            pings.report(get_tls_message(dest, i, send_time, result), &tx);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let Some(tls_config) = tls_runner_config(dest, &[], false, &tx) else {
+                return;
+            };
+
+            let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+            while let Some(i) = pings.next_ping_async().await {
+                let send_time = OffsetDateTime::now_utc();
+                let result = tls_ping_async(&config, dest, &tls_config).await;
+                pings.report(get_tls_message(dest, i, send_time, result), &tx);
+            }
+        }))
+    }
+}
+
+fn tls_ping(
+    config: &MPingerConfigShared,
+    dest: &MPingDestination,
+    tls_config: &Arc<ClientConfig>,
+) -> Result<MPingerTlsDetails, MPingerResult> {
+    let sock = dest.socket_addr();
+    let connect_start = Instant::now();
+    let stream = match connect(dest.timeout(config), sock) {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("Error connecting to {}: {}", sock, e);
+            return Err(MPingerResult::from_io_error(&e));
+        }
+    };
+
+    let (mut stream, tls) = tls_handshake(stream, dest, tls_config, connect_start.elapsed())?;
+
+    // close the session properly, the server doesn't have to wait for a timeout
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+
+    Ok(tls)
+}
+
+#[cfg(feature = "tokio")]
+async fn tls_ping_async(
+    config: &MPingerConfigShared,
+    dest: &MPingDestination,
+    tls_config: &Arc<ClientConfig>,
+) -> Result<MPingerTlsDetails, MPingerResult> {
+    let sock = dest.socket_addr();
+    let connect_start = Instant::now();
+    let stream = match connect_async(dest.timeout(config), sock).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("Error connecting to {}: {}", sock, e);
+            return Err(MPingerResult::from_io_error(&e));
+        }
+    };
+
+    let (mut stream, tls) =
+        tls_handshake_async(config, stream, dest, tls_config, connect_start.elapsed()).await?;
+
+    let _ = tokio::time::timeout(dest.timeout(config), stream.shutdown()).await;
+
+    Ok(tls)
+}
+
+fn get_tls_message(
+    dest: &MPingDestination,
+    ping_nr: usize,
+    send_time: OffsetDateTime,
+    result: Result<MPingerTlsDetails, MPingerResult>,
+) -> MPingerMessage {
+    match result {
+        Ok(tls) => MPingerMessage::new_ping(
+            dest,
+            ping_nr,
+            send_time,
+            tls.handshake_time,
+            dest.check_tls(&tls),
+        )
+        .with_details(MPingerDetails::Tls(tls)),
+        Err(result) => MPingerMessage::new_ping(dest, ping_nr, send_time, Duration::ZERO, result),
+    }
+}
+
+// HTTP keepalive pings over TLS
+pub struct MPingerHTTPSKeepAlive();
+
+impl Runner for MPingerHTTPSKeepAlive {
+    fn name(&self) -> &str {
+        "HTTPS Keep Alive"
+    }
+
+    fn default_port(&self) -> Option<u16> {
+        Some(443)
+    }
+
+    fn start(
+        &self,
+        config: MPingerConfigShared,
+        dest: &MPingDestination,
+        tx: mpsc::Sender<MPingerMessage>,
+        count: usize,
+        stop_signal: &MPingerStopSignal,
+    ) {
+        let Some(tls_config) = tls_runner_config(dest, &[b"http/1.1"], true, &tx) else {
+            return;
+        };
+
+        let conn = HttpConnection::new_tls(dest.socket_addr(), tls_config);
+        run_http(config, dest, tx, count, stop_signal, conn);
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let Some(tls_config) = tls_runner_config(dest, &[b"http/1.1"], true, &tx) else {
+                return;
+            };
+
+            let conn = HttpConnection::new_tls(dest.socket_addr(), tls_config);
+            run_http_async(config, dest, tx, count, stop_signal, conn).await;
+        }))
+    }
+}
+
+// client configuration of a runner, a configuration error is reported as the only message
+fn tls_runner_config(
+    dest: &MPingDestination,
+    alpn_protocols: &[&[u8]],
+    verify: bool,
+    tx: &impl MessageSender,
+) -> Option<Arc<ClientConfig>> {
+    match tls_client_config(dest, alpn_protocols, verify) {
+        Ok(tls_config) => Some(tls_config),
+        Err(e) => {
+            error!("Error configuring TLS: {}", e);
+            tx.send_message(MPingerMessage::new_error(dest, 0, MPingerResult::Error));
+            None
+        }
+    }
+}
+
+// client configuration for the TLS options of the destination, offering the given ALPN protocols,
+// if verify is false the handshake accepts any certificate, as it does with skip_verify
+pub(crate) fn tls_client_config(
+    dest: &MPingDestination,
+    alpn_protocols: &[&[u8]],
+    verify: bool,
+) -> Result<Arc<ClientConfig>, String> {
+    let skip_verify = !verify || dest.tls.as_ref().is_some_and(|tls| tls.skip_verify);
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+
+    let mut tls_config = if skip_verify {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+            .with_no_client_auth()
+    } else {
+        builder
+            .with_root_certificates(root_certificates(dest))
+            .with_no_client_auth()
+    };
+    tls_config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+
+    Ok(Arc::new(tls_config))
+}
+
+// name sent as SNI and verified by the certificate
+pub(crate) fn get_server_name(
+    dest: &MPingDestination,
+) -> Result<ServerName<'static>, MPingerResult> {
+    let name = dest
+        .tls
+        .as_ref()
+        .and_then(|tls| tls.server_name.clone())
+        .unwrap_or_else(|| dest.host.clone());
+
+    ServerName::try_from(name).map_err(|e| {
+        debug!("Invalid TLS server name: {}", e);
+        MPingerResult::Error
+    })
+}
+
+// TLS handshake on a connected socket, connect_time is how long the TCP connect took
+pub(crate) fn tls_handshake(
+    mut stream: TcpStream,
+    dest: &MPingDestination,
+    tls_config: &Arc<ClientConfig>,
+    connect_time: Duration,
+) -> Result<(StreamOwned<ClientConnection, TcpStream>, MPingerTlsDetails), MPingerResult> {
+    let server_name = get_server_name(dest)?;
+    let mut conn = ClientConnection::new(tls_config.clone(), server_name.clone()).map_err(|e| {
+        debug!("Error starting TLS: {}", e);
+        MPingerResult::TlsError
+    })?;
+
+    let handshake_start = Instant::now();
+    while conn.is_handshaking() {
+        if let Err(e) = conn.complete_io(&mut stream) {
+            return Err(get_tls_result(&e));
+        }
+    }
+    let handshake_time = handshake_start.elapsed();
+
+    let tls = get_tls_details(dest, &conn, &server_name, connect_time, handshake_time);
+    Ok((StreamOwned::new(conn, stream), tls))
+}
+
+// async counterpart of tls_handshake
+#[cfg(feature = "tokio")]
+pub(crate) async fn tls_handshake_async(
+    config: &MPingerConfigShared,
+    stream: tokio::net::TcpStream,
+    dest: &MPingDestination,
+    tls_config: &Arc<ClientConfig>,
+    connect_time: Duration,
+) -> Result<
+    (
+        tokio_rustls::client::TlsStream<tokio::net::TcpStream>,
+        MPingerTlsDetails,
+    ),
+    MPingerResult,
+> {
+    let server_name = get_server_name(dest)?;
+    let connector = tokio_rustls::TlsConnector::from(tls_config.clone());
+
+    let handshake_start = Instant::now();
+    let stream = tokio::time::timeout(
+        dest.timeout(config),
+        connector.connect(server_name.clone(), stream),
+    )
+    .await
+    .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+    .map_err(|e| get_tls_result(&e))?;
+    let handshake_time = handshake_start.elapsed();
+
+    let tls = get_tls_details(
+        dest,
+        stream.get_ref().1,
+        &server_name,
+        connect_time,
+        handshake_time,
+    );
+    Ok((stream, tls))
+}
+
+// rustls reports TLS errors as io::Error wrapping a rustls::Error
+pub(crate) fn get_tls_result(e: &std::io::Error) -> MPingerResult {
+    match e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+    {
+        Some(rustls::Error::InvalidCertificate(cert_error)) => {
+            debug!("Invalid TLS certificate: {}", cert_error);
+            MPingerResult::TlsCertificate(get_chain_status(cert_error))
+        }
+        Some(tls_error) => {
+            debug!("TLS handshake failed: {}", tls_error);
+            MPingerResult::TlsError
+        }
+        None => {
+            debug!("Error during the TLS handshake: {}", e);
+            MPingerResult::from_io_error(e)
+        }
+    }
+}
+
+pub(crate) fn get_tls_details(
+    dest: &MPingDestination,
+    conn: &CommonState,
+    server_name: &ServerName<'_>,
+    connect_time: Duration,
+    handshake_time: Duration,
+) -> MPingerTlsDetails {
+    let version = match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+        Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+        Some(version) => format!("{:?}", version),
+        None => String::new(),
+    };
+    let cipher = conn
+        .negotiated_cipher_suite()
+        .map(|suite| format!("{:?}", suite.suite()))
+        .unwrap_or_default();
+
+    // the first certificate is the one of the server, followed by the intermediates
+    let certs = conn.peer_certificates().unwrap_or_default();
+    let cert_not_after = certs
+        .first()
+        .and_then(|cert| X509Certificate::from_der(cert).ok())
+        .map(|(_, cert)| cert.validity().not_after.to_datetime());
+    let cert_expires_in_days =
+        cert_not_after.map(|not_after| (not_after - OffsetDateTime::now_utc()).whole_days());
+
+    let chain_status = match certs.split_first() {
+        Some((end_entity, intermediates)) => match chain_verifier(dest).verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            &[],
+            UnixTime::now(),
+        ) {
+            Ok(_) => MPingerTlsChainStatus::Valid,
+            Err(rustls::Error::InvalidCertificate(cert_error)) => get_chain_status(&cert_error),
+            Err(e) => {
+                debug!("Error verifying the certificate chain: {}", e);
+                MPingerTlsChainStatus::Invalid
+            }
+        },
+        None => MPingerTlsChainStatus::Unknown,
+    };
+
+    MPingerTlsDetails {
+        version,
+        cipher,
+        connect_time,
+        handshake_time,
+        cert_not_after,
+        cert_expires_in_days,
+        chain_status,
+    }
+}
+
+// the webpki roots and the extra ones of the destination
+fn root_certificates(dest: &MPingDestination) -> RootCertStore {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    for ca_certificate in ca_certificates(dest) {
+        if let Err(e) = roots.add(CertificateDer::from(ca_certificate.as_slice())) {
+            error!("Invalid CA certificate: {}", e);
+        }
+    }
+
+    roots
+}
+
+fn ca_certificates(dest: &MPingDestination) -> &[Vec<u8>] {
+    dest.tls
+        .as_ref()
+        .map(|tls| tls.ca_certificates.as_slice())
+        .unwrap_or_default()
+}
+
+// verifies the certificate chains after the handshake, whatever the handshake accepted
+fn chain_verifier(dest: &MPingDestination) -> Arc<WebPkiServerVerifier> {
+    let new_verifier = || {
+        WebPkiServerVerifier::builder_with_provider(
+            Arc::new(root_certificates(dest)),
+            Arc::new(rustls::crypto::ring::default_provider()),
+        )
+        .build()
+        .expect("the root certificate store is not empty")
+    };
+
+    // the one with the webpki roots only is shared
+    static VERIFIER: OnceLock<Arc<WebPkiServerVerifier>> = OnceLock::new();
+    match ca_certificates(dest).is_empty() {
+        true => VERIFIER.get_or_init(new_verifier).clone(),
+        false => new_verifier(),
+    }
+}
+
+fn get_chain_status(cert_error: &CertificateError) -> MPingerTlsChainStatus {
+    match cert_error {
+        CertificateError::Expired | CertificateError::ExpiredContext { .. } => {
+            MPingerTlsChainStatus::Expired
+        }
+        CertificateError::NotValidYet | CertificateError::NotValidYetContext { .. } => {
+            MPingerTlsChainStatus::NotValidYet
+        }
+        CertificateError::UnknownIssuer => MPingerTlsChainStatus::UnknownIssuer,
+        CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {
+            MPingerTlsChainStatus::NotValidForName
+        }
+        CertificateError::Revoked => MPingerTlsChainStatus::Revoked,
+        _ => MPingerTlsChainStatus::Invalid,
+    }
+}
+
+// Accepts any certificate, the handshake signatures are still checked
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpinger::{
+        MPinger, MPingerConfig, MPingerHttpRequest, MPingerMessageKind, MPingerTlsOptions,
+        MPingerType,
+    };
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::io::Read;
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    // HTTPS server on 127.0.0.1 with a certificate for localhost issued by a new CA,
+    // returns its address and the certificate of the CA
+    fn start_server() -> (SocketAddr, Vec<u8>) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_cert = ca_params.self_signed(&ca_key).unwrap();
+        let issuer = Issuer::new(ca_params, ca_key);
+
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&key, &issuer)
+            .unwrap();
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server_config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone()],
+                PrivatePkcs8KeyDer::from(key.serialize_der()).into(),
+            )
+            .unwrap();
+        let server_config = Arc::new(server_config);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let conn = ServerConnection::new(server_config.clone()).unwrap();
+                let mut stream = StreamOwned::new(conn, stream.unwrap());
+                thread::spawn(move || {
+                    // an empty response to every request
+                    let mut request = Vec::new();
+                    let mut buffer = [0u8; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buffer) {
+                        request.extend_from_slice(&buffer[..n]);
+                        if request.ends_with(b"\r\n\r\n") {
+                            request.clear();
+                            let response = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+                            if stream.write_all(response).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        (addr, ca_cert.der().to_vec())
+    }
+
+    fn new_pinger(ping_type: MPingerType, addr: SocketAddr, ca: Option<&[u8]>) -> MPinger {
+        let tls = MPingerTlsOptions {
+            server_name: Some("localhost".to_string()),
+            ca_certificates: ca.into_iter().map(|ca| ca.to_vec()).collect(),
+            ..Default::default()
+        };
+
+        let mut pinger = MPinger::new(MPingerConfig::default());
+        let addr = addr.to_string();
+        match ping_type {
+            MPingerType::HTTPSKeepAlive => {
+                pinger.add_https_destination(&addr, MPingerHttpRequest::default(), tls)
+            }
+            _ => pinger.add_tls_destination(ping_type, &addr, tls),
+        }
+        .unwrap();
+        pinger
+    }
+
+    // result of a single ping with threads, and with tokio, trusting the CA of the server or not
+    fn ping(ping_type: MPingerType, trust_ca: bool) -> Vec<MPingerMessage> {
+        let (addr, ca) = start_server();
+        let ca = trust_ca.then_some(ca.as_slice());
+
+        #[allow(unused_mut)]
+        let mut messages = vec![new_pinger(ping_type, addr, ca)
+            .start(1)
+            .find(|message| message.kind == MPingerMessageKind::Ping)
+            .unwrap()];
+
+        #[cfg(feature = "tokio")]
+        {
+            use futures_core::Stream;
+            use std::pin::Pin;
+
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            messages.push(runtime.block_on(async {
+                let mut stream = new_pinger(ping_type, addr, ca).start_async(1);
+                loop {
+                    let message = std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx))
+                        .await
+                        .unwrap();
+                    if message.kind == MPingerMessageKind::Ping {
+                        return message;
+                    }
+                }
+            }));
+        }
+
+        messages
+    }
+
+    fn tls_details(message: &MPingerMessage) -> &MPingerTlsDetails {
+        match &message.details {
+            MPingerDetails::Tls(tls) => tls,
+            MPingerDetails::Http(response) => response.tls.as_ref().unwrap(),
+            details => panic!("No TLS details: {:?}", details),
+        }
+    }
+
+    #[test]
+    fn tls_handshake_with_trusted_ca() {
+        for message in ping(MPingerType::TLSHandshake, true) {
+            assert_eq!(message.result, MPingerResult::Success);
+            let tls = tls_details(&message);
+            assert_eq!(tls.chain_status, MPingerTlsChainStatus::Valid);
+            assert!(!tls.version.is_empty());
+            assert!(tls.cert_expires_in_days.is_some());
+        }
+    }
+
+    #[test]
+    fn tls_handshake_with_unknown_issuer() {
+        // the handshake is done anyway, the certificate is reported with the failure
+        for message in ping(MPingerType::TLSHandshake, false) {
+            let status = MPingerTlsChainStatus::UnknownIssuer;
+            assert_eq!(message.result, MPingerResult::TlsCertificate(status));
+            assert_eq!(tls_details(&message).chain_status, status);
+        }
+    }
+
+    #[test]
+    fn https_with_trusted_ca() {
+        for message in ping(MPingerType::HTTPSKeepAlive, true) {
+            assert_eq!(message.result, MPingerResult::Success);
+            assert_eq!(
+                tls_details(&message).chain_status,
+                MPingerTlsChainStatus::Valid
+            );
+        }
+    }
+
+    #[test]
+    fn https_with_unknown_issuer() {
+        // the certificate is verified by the handshake, which fails
+        for message in ping(MPingerType::HTTPSKeepAlive, false) {
+            assert_eq!(
+                message.result,
+                MPingerResult::TlsCertificate(MPingerTlsChainStatus::UnknownIssuer)
+            );
+        }
+    }
+}
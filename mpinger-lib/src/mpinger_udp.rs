@@ -1,103 +1,737 @@
-use crate::mpinger::{MPingDestination, MPingerConfigShared, MPingerMessage, MPingerType};
+use crate::mpinger::{
+    MPingDestination, MPingerConfigShared, MPingerDetails, MPingerMessage, MPingerResult,
+    MPingerUdpDetails,
+};
+#[cfg(feature = "tokio")]
+use crate::mpinger_runner::RunnerFuture;
+use crate::mpinger_runner::{MPingerStopSignal, PingLoop, Runner};
 use log::{debug, error};
-use socket2::{Domain, Protocol, Socket, Type};
-use std::mem::MaybeUninit;
+use std::net::{SocketAddr, UdpSocket};
 use std::sync::mpsc;
-use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use time::OffsetDateTime;
 
+// probe payload, big endian, times in ns since the Unix epoch:
+//   magic (4), version (1), kind (1), reserved (2), sequence number (8), client send time (8),
+//   reflector receive time (8), reflector send time (8)
+// the probe already has room for the times of the reflector, so the reply is the same size
+const UDP_MAGIC: &[u8; 4] = b"MPNG";
+const UDP_VERSION: u8 = 1;
+const UDP_KIND_PROBE: u8 = 0;
+const UDP_KIND_REFLECTED: u8 = 1;
+pub(crate) const UDP_PAYLOAD_SIZE: usize = 40;
+const UDP_BUFFER_SIZE: usize = 65536;
+// sequence numbers this far behind the highest one received can't be told apart from duplicates
+const UDP_REPLY_WINDOW: u64 = 64;
+
 pub struct MPingerUDP();
 
-impl MPingerUDP {
-    pub fn start(
+impl Runner for MPingerUDP {
+    fn name(&self) -> &str {
+        "UDP ping"
+    }
+
+    fn default_port(&self) -> Option<u16> {
+        Some(8888)
+    }
+
+    fn start(
+        &self,
         config: MPingerConfigShared,
         dest: &MPingDestination,
         tx: mpsc::Sender<MPingerMessage>,
         count: usize,
+        stop_signal: &MPingerStopSignal,
     ) {
-        let mut i = 0;
-        loop {
-            // Create UDP socket
-            let socket = match Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)) {
-                Ok(socket) => socket,
-                Err(e) => {
-                    error!("Error creating UDP socket: {:?}", e);
-                    continue;
-                }
-            };
-            socket
-                .set_read_timeout(Some(std::time::Duration::from_millis(
-                    config.read().unwrap().timeout,
-                )))
-                .unwrap();
-            socket
-                .set_write_timeout(Some(std::time::Duration::from_millis(
-                    config.read().unwrap().timeout,
-                )))
-                .unwrap();
-
-            // Send UDP message
-            let message = b"ping";
-            let start_time = Instant::now();
+        // one socket for all the probes, replies to earlier ones can still be told apart
+        let mut socket: Option<(UdpSocket, SocketAddr)> = None;
+        let mut replies = UdpReplies::default();
+        let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+        // retries are new probes, replies to the failed attempts are late ones
+        let mut next_seq = 0;
 
-            if let Some(addr) = dest.sock_addr.as_socket() {
-                let ip_str = addr.to_string();
-                debug!("Sending UDP ping to {:?}", ip_str);
+        let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+        while let Some(i) = pings.next_ping() {
+            let sock_addr = dest.socket_addr();
+            let timeout = dest.timeout(&config);
+
+            // a new socket if the address of the destination changed
+            if !matches!(&socket, Some((_, addr)) if *addr == sock_addr) {
+                socket = match udp_connect(sock_addr) {
+                    Ok(udp_socket) => Some((udp_socket, sock_addr)),
+                    Err(e) => {
+                        error!("Error creating UDP socket: {:?}", e);
+                        pings.abort(
+                            MPingerMessage::new_error(dest, i, MPingerResult::Error),
+                            &tx,
+                        );
+                        continue;
+                    }
+                };
             }
+            let (udp_socket, _) = socket.as_ref().unwrap();
 
-            let send_result = socket.send_to(message, &dest.sock_addr);
-            if let Err(e) = send_result {
-                debug!("Error sending UDP packet: {:?}", e);
-                let _ = tx.send(MPingerMessage {
-                    destination_id: dest.id,
-                    ping_nr: i,
-                    runner_type: MPingerType::UDPPing,
-                    start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                    duration: 0,
-                    is_error: true,
+            let mut probe = UdpProbe::new(dest, next_seq);
+            next_seq += 1;
+            debug!("Sending UDP ping {} to {}", probe.details.seq, sock_addr);
+
+            let start_time = Instant::now();
+            let result = udp_socket
+                .set_write_timeout(Some(timeout))
+                .and_then(|_| udp_socket.send(&probe.payload))
+                .map(|_| {
+                    udp_receive(udp_socket, start_time + timeout, &mut buffer, |reply| {
+                        probe.check_reply(&mut replies, reply)
+                    })
                 });
-                i += 1;
-                if count > 0 && i >= count {
-                    break;
+            pings.report(probe.get_message(dest, i, start_time, result), &tx);
+        }
+    }
+
+    #[cfg(feature = "tokio")]
+    fn start_async<'a>(
+        &'a self,
+        config: MPingerConfigShared,
+        dest: &'a MPingDestination,
+        tx: tokio::sync::mpsc::UnboundedSender<MPingerMessage>,
+        count: usize,
+        stop_signal: &'a MPingerStopSignal,
+    ) -> Option<RunnerFuture<'a>> {
+        Some(Box::pin(async move {
+            let mut socket: Option<(tokio::net::UdpSocket, SocketAddr)> = None;
+            let mut replies = UdpReplies::default();
+            let mut buffer = vec![0u8; UDP_BUFFER_SIZE];
+            let mut next_seq = 0;
+
+            let mut pings = PingLoop::new(&config, dest, count, stop_signal);
+            while let Some(i) = pings.next_ping_async().await {
+                let sock_addr = dest.socket_addr();
+                let timeout = dest.timeout(&config);
+
+                if !matches!(&socket, Some((_, addr)) if *addr == sock_addr) {
+                    socket = match udp_connect_async(sock_addr).await {
+                        Ok(udp_socket) => Some((udp_socket, sock_addr)),
+                        Err(e) => {
+                            error!("Error creating UDP socket: {:?}", e);
+                            let message = MPingerMessage::new_error(dest, i, MPingerResult::Error);
+                            pings.abort(message, &tx);
+                            continue;
+                        }
+                    };
                 }
-                thread::sleep(std::time::Duration::from_millis(
-                    config.read().unwrap().ping_interval,
-                ));
-                continue;
+                let (udp_socket, _) = socket.as_ref().unwrap();
+
+                let mut probe = UdpProbe::new(dest, next_seq);
+                next_seq += 1;
+                debug!("Sending UDP ping {} to {}", probe.details.seq, sock_addr);
+
+                let start_time = Instant::now();
+                let deadline = tokio::time::Instant::from_std(start_time + timeout);
+                let result =
+                    match tokio::time::timeout_at(deadline, udp_socket.send(&probe.payload))
+                        .await
+                        .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))
+                    {
+                        Ok(_) => Ok(udp_receive_async(
+                            udp_socket,
+                            deadline,
+                            &mut buffer,
+                            |reply| probe.check_reply(&mut replies, reply),
+                        )
+                        .await),
+                        Err(e) => Err(e),
+                    };
+                pings.report(probe.get_message(dest, i, start_time, result), &tx);
             }
+        }))
+    }
+}
 
-            // Wait for response
-            let mut buf = [MaybeUninit::<u8>::uninit(); 1024];
-            let mut is_error = false;
-            let duration = match socket.recv_from(&mut buf) {
-                Ok(_) => start_time.elapsed().as_micros() as u64,
-                Err(e) => {
-                    debug!("Error receiving UDP response: {:?}", e);
-                    is_error = true;
-                    0
-                }
-            };
+// Probe of a single ping, the reply has to be an echo of its payload
+struct UdpProbe {
+    send_time: OffsetDateTime,
+    payload: Vec<u8>,
+    details: MPingerUdpDetails,
+}
+
+impl UdpProbe {
+    fn new(dest: &MPingDestination, seq: u64) -> Self {
+        let send_time = OffsetDateTime::now_utc();
+        let payload = udp_payload(dest, &MPingerUdpPacket::new(seq, send_time));
+        let details = MPingerUdpDetails {
+            seq,
+            payload_size: payload.len(),
+            ..Default::default()
+        };
+
+        Self {
+            send_time,
+            payload,
+            details,
+        }
+    }
 
-            let result = tx.send(MPingerMessage {
-                destination_id: dest.id,
-                ping_nr: i,
-                runner_type: MPingerType::UDPPing,
-                start_timestamp: OffsetDateTime::now_utc().unix_timestamp(),
-                duration,
-                is_error,
-            });
-
-            if result.is_err() {
-                debug!("Error sending message: {:?}", result);
+    fn check_reply(&mut self, replies: &mut UdpReplies, reply: &[u8]) -> Option<MPingerResult> {
+        check_udp_reply(replies, &mut self.details, &self.payload, reply)
+    }
+
+    // result is the one of sending the probe, holding the one of waiting for the reply
+    fn get_message(
+        self,
+        dest: &MPingDestination,
+        ping_nr: usize,
+        start_time: Instant,
+        result: std::io::Result<std::io::Result<MPingerResult>>,
+    ) -> MPingerMessage {
+        let (rtt, result) = match result {
+            Ok(Ok(MPingerResult::Success)) => (start_time.elapsed(), MPingerResult::Success),
+            Ok(Ok(result)) => (Duration::ZERO, result),
+            Ok(Err(e)) => {
+                debug!("Error receiving UDP response: {:?}", e);
+                (Duration::ZERO, MPingerResult::from_io_error(&e))
+            }
+            Err(e) => {
+                debug!("Error sending UDP packet: {:?}", e);
+                (Duration::ZERO, MPingerResult::SendError)
             }
+        };
+
+        MPingerMessage::new_ping(dest, ping_nr, self.send_time, rtt, result)
+            .with_details(MPingerDetails::Udp(self.details))
+    }
+}
+
+// connected, so ICMP Port Unreachable is reported as connection refused
+fn udp_connect(sock_addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(udp_bind_addr(sock_addr))?;
+    socket.connect(sock_addr)?;
 
-            i += 1;
-            if count > 0 && i >= count {
-                break;
+    Ok(socket)
+}
+
+#[cfg(feature = "tokio")]
+async fn udp_connect_async(sock_addr: SocketAddr) -> std::io::Result<tokio::net::UdpSocket> {
+    let socket = tokio::net::UdpSocket::bind(udp_bind_addr(sock_addr)).await?;
+    socket.connect(sock_addr).await?;
+
+    Ok(socket)
+}
+
+// any local address of the family of the destination
+pub(crate) fn udp_bind_addr(sock_addr: SocketAddr) -> SocketAddr {
+    if sock_addr.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    }
+}
+
+// reads replies until check_reply returns the result of the probe or the deadline passes
+fn udp_receive<F>(
+    socket: &UdpSocket,
+    deadline: Instant,
+    buffer: &mut [u8],
+    mut check_reply: F,
+) -> std::io::Result<MPingerResult>
+where
+    F: FnMut(&[u8]) -> Option<MPingerResult>,
+{
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let n = socket.recv(buffer)?;
+        if let Some(result) = check_reply(&buffer[..n]) {
+            return Ok(result);
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+async fn udp_receive_async<F>(
+    socket: &tokio::net::UdpSocket,
+    deadline: tokio::time::Instant,
+    buffer: &mut [u8],
+    mut check_reply: F,
+) -> std::io::Result<MPingerResult>
+where
+    F: FnMut(&[u8]) -> Option<MPingerResult>,
+{
+    loop {
+        let n = tokio::time::timeout_at(deadline, socket.recv(buffer))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()))?;
+        if let Some(result) = check_reply(&buffer[..n]) {
+            return Ok(result);
+        }
+    }
+}
+
+// UDP probe, echoed back unchanged or stamped by a reflector like mpinger-srv
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MPingerUdpPacket {
+    pub seq: u64,
+    pub send_time: OffsetDateTime,
+    // set by the reflector
+    pub reflector_receive_time: Option<OffsetDateTime>,
+    pub reflector_send_time: Option<OffsetDateTime>,
+}
+
+impl MPingerUdpPacket {
+    pub fn new(seq: u64, send_time: OffsetDateTime) -> Self {
+        MPingerUdpPacket {
+            seq,
+            send_time,
+            reflector_receive_time: None,
+            reflector_send_time: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let kind = match self.reflector_send_time {
+            Some(_) => UDP_KIND_REFLECTED,
+            None => UDP_KIND_PROBE,
+        };
+
+        let mut payload = Vec::with_capacity(UDP_PAYLOAD_SIZE);
+        payload.extend_from_slice(UDP_MAGIC);
+        payload.extend_from_slice(&[UDP_VERSION, kind, 0, 0]);
+        payload.extend_from_slice(&self.seq.to_be_bytes());
+        payload.extend_from_slice(&to_udp_time(Some(self.send_time)));
+        payload.extend_from_slice(&to_udp_time(self.reflector_receive_time));
+        payload.extend_from_slice(&to_udp_time(self.reflector_send_time));
+
+        payload
+    }
+
+    // None if the payload isn't a probe of a known version
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() < UDP_PAYLOAD_SIZE
+            || !payload.starts_with(UDP_MAGIC)
+            || payload[4] != UDP_VERSION
+        {
+            return None;
+        }
+
+        let reflected = payload[5] == UDP_KIND_REFLECTED;
+        Some(MPingerUdpPacket {
+            seq: u64::from_be_bytes(payload[8..16].try_into().unwrap()),
+            send_time: from_udp_time(&payload[16..24])?,
+            reflector_receive_time: from_udp_time(&payload[24..32]).filter(|_| reflected),
+            reflector_send_time: from_udp_time(&payload[32..40]).filter(|_| reflected),
+        })
+    }
+
+    // stamps a received probe in place before it is sent back, false if it isn't a probe
+    pub fn reflect(payload: &mut [u8], receive_time: OffsetDateTime) -> bool {
+        match MPingerUdpPacket::parse(payload) {
+            Some(packet) if packet.reflector_send_time.is_none() => {}
+            _ => return false,
+        }
+
+        payload[5] = UDP_KIND_REFLECTED;
+        payload[24..32].copy_from_slice(&to_udp_time(Some(receive_time)));
+        payload[32..40].copy_from_slice(&to_udp_time(Some(OffsetDateTime::now_utc())));
+
+        true
+    }
+}
+
+// the probe padded with zeros to the payload size of the destination, if it is larger
+fn udp_payload(dest: &MPingDestination, packet: &MPingerUdpPacket) -> Vec<u8> {
+    let mut payload = packet.to_bytes();
+    if let Some(payload_size) = dest.payload_size() {
+        payload.resize(payload_size.max(UDP_PAYLOAD_SIZE), 0);
+    }
+
+    payload
+}
+
+// zero if the time is not set
+fn to_udp_time(time: Option<OffsetDateTime>) -> [u8; 8] {
+    let nanos = time.map_or(0, |time| time.unix_timestamp_nanos() as u64);
+    nanos.to_be_bytes()
+}
+
+fn from_udp_time(bytes: &[u8]) -> Option<OffsetDateTime> {
+    match u64::from_be_bytes(bytes.try_into().ok()?) {
+        0 => None,
+        nanos => OffsetDateTime::from_unix_timestamp_nanos(nanos as i128).ok(),
+    }
+}
+
+// result of the probe if the reply ends the wait for it, None for replies to earlier probes
+fn check_udp_reply(
+    replies: &mut UdpReplies,
+    details: &mut MPingerUdpDetails,
+    payload: &[u8],
+    reply: &[u8],
+) -> Option<MPingerResult> {
+    let receive_time = OffsetDateTime::now_utc();
+
+    let packet = match MPingerUdpPacket::parse(reply) {
+        Some(packet) if packet.seq <= details.seq => packet,
+        _ => {
+            debug!("Invalid UDP reply of {} bytes", reply.len());
+            return Some(MPingerResult::UdpPayloadMismatch);
+        }
+    };
+    let seq = packet.seq;
+
+    if seq == details.seq {
+        // an echo or the probe stamped by the reflector, anything else is corrupted
+        let echoed = match packet.reflector_send_time {
+            Some(_) => {
+                let mut probe = reply.to_vec();
+                probe[5] = UDP_KIND_PROBE;
+                probe[24..40].fill(0);
+                probe == payload
+            }
+            None => reply == payload,
+        };
+        if !echoed {
+            debug!("Corrupted UDP reply to ping {}", seq);
+            return Some(MPingerResult::UdpPayloadMismatch);
+        }
+
+        replies.add(seq);
+        if let (Some(reflector_receive_time), Some(reflector_send_time)) =
+            (packet.reflector_receive_time, packet.reflector_send_time)
+        {
+            details.forward_delay = Some(reflector_receive_time - packet.send_time);
+            details.reverse_delay = Some(receive_time - reflector_send_time);
+            details.reflector_time = (reflector_send_time - reflector_receive_time)
+                .try_into()
+                .ok();
+        }
+        return Some(MPingerResult::Success);
+    }
+
+    match replies.add(seq) {
+        UdpReply::Duplicate => {
+            debug!("Duplicate UDP reply to ping {}", seq);
+            details.duplicate_replies += 1;
+        }
+        UdpReply::New { reordered } => {
+            debug!("Late UDP reply to ping {}", seq);
+            details.late_replies += 1;
+            if reordered {
+                details.reordered_replies += 1;
+            }
+        }
+    }
+
+    None
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum UdpReply {
+    // reordered if a reply to a later probe was received before
+    New { reordered: bool },
+    Duplicate,
+}
+
+// Sequence numbers of the replies received so far
+#[derive(Debug, Default)]
+pub(crate) struct UdpReplies {
+    highest: Option<u64>,
+    // bit n is set if the reply to highest - n was received
+    window: u64,
+}
+
+impl UdpReplies {
+    pub fn add(&mut self, seq: u64) -> UdpReply {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.highest = Some(seq);
+                self.window = 1;
+                return UdpReply::New { reordered: false };
             }
-            thread::sleep(std::time::Duration::from_millis(
-                config.read().unwrap().ping_interval,
+        };
+
+        if seq > highest {
+            let shift = seq - highest;
+            self.window = if shift >= UDP_REPLY_WINDOW {
+                1
+            } else {
+                (self.window << shift) | 1
+            };
+            self.highest = Some(seq);
+            return UdpReply::New { reordered: false };
+        }
+
+        let age = highest - seq;
+        if age >= UDP_REPLY_WINDOW {
+            return UdpReply::New { reordered: true };
+        }
+        if self.window & (1 << age) != 0 {
+            return UdpReply::Duplicate;
+        }
+        self.window |= 1 << age;
+
+        UdpReply::New { reordered: true }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // payload of the probe padded to 64 bytes, and its details before any reply
+    fn probe(seq: u64) -> (Vec<u8>, MPingerUdpDetails) {
+        let mut payload = MPingerUdpPacket::new(seq, OffsetDateTime::now_utc()).to_bytes();
+        payload.resize(64, 0);
+        let details = MPingerUdpDetails {
+            seq,
+            payload_size: payload.len(),
+            ..Default::default()
+        };
+        (payload, details)
+    }
+
+    #[test]
+    fn replies_window() {
+        let mut replies = UdpReplies::default();
+        assert_eq!(replies.add(10), UdpReply::New { reordered: false });
+        assert_eq!(replies.add(10), UdpReply::Duplicate);
+        assert_eq!(replies.add(12), UdpReply::New { reordered: false });
+        // inside the window
+        assert_eq!(replies.add(11), UdpReply::New { reordered: true });
+        assert_eq!(replies.add(11), UdpReply::Duplicate);
+        assert_eq!(replies.add(10), UdpReply::Duplicate);
+
+        // the oldest sequence number still in the window after a shift
+        let highest = 12 + UDP_REPLY_WINDOW - 1;
+        assert_eq!(replies.add(highest), UdpReply::New { reordered: false });
+        assert_eq!(replies.add(12), UdpReply::Duplicate);
+        // older ones can't be told from duplicates anymore
+        assert_eq!(replies.add(11), UdpReply::New { reordered: true });
+        assert_eq!(replies.add(11), UdpReply::New { reordered: true });
+    }
+
+    #[test]
+    fn replies_window_reset() {
+        let mut replies = UdpReplies::default();
+        assert_eq!(replies.add(0), UdpReply::New { reordered: false });
+        assert_eq!(replies.add(1), UdpReply::New { reordered: false });
+
+        // a shift by the window size or more forgets every earlier reply
+        let highest = 1 + UDP_REPLY_WINDOW;
+        assert_eq!(replies.add(highest), UdpReply::New { reordered: false });
+        assert_eq!(replies.add(highest), UdpReply::Duplicate);
+        assert_eq!(replies.add(2), UdpReply::New { reordered: true });
+        assert_eq!(replies.add(2), UdpReply::Duplicate);
+        assert_eq!(replies.add(1), UdpReply::New { reordered: true });
+
+        let highest = highest + 1000;
+        assert_eq!(replies.add(highest), UdpReply::New { reordered: false });
+        assert_eq!(replies.add(highest - 1), UdpReply::New { reordered: true });
+    }
+
+    #[test]
+    fn duplicate_reply() {
+        let mut replies = UdpReplies::default();
+        let (payload_1, mut details) = probe(1);
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload_1, &payload_1),
+            Some(MPingerResult::Success)
+        );
+        assert_eq!(details.duplicate_replies, 0);
+
+        // the reply to probe 1 comes again while waiting for the one to probe 2
+        let (payload_2, mut details) = probe(2);
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload_2, &payload_1),
+            None
+        );
+        assert_eq!(details.duplicate_replies, 1);
+        assert_eq!(details.late_replies, 0);
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload_2, &payload_2),
+            Some(MPingerResult::Success)
+        );
+    }
+
+    #[test]
+    fn late_and_reordered_replies() {
+        let mut replies = UdpReplies::default();
+        // probes 1 and 2 time out
+        let (payload_1, _) = probe(1);
+        let (payload_2, _) = probe(2);
+
+        // the reply to probe 2 is late, but in order
+        let (payload_3, mut details) = probe(3);
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload_3, &payload_2),
+            None
+        );
+        assert_eq!(details.late_replies, 1);
+        assert_eq!(details.reordered_replies, 0);
+
+        // the reply to probe 1 comes after the one to probe 2
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload_3, &payload_1),
+            None
+        );
+        assert_eq!(details.late_replies, 2);
+        assert_eq!(details.reordered_replies, 1);
+
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload_3, &payload_3),
+            Some(MPingerResult::Success)
+        );
+        assert_eq!(details.late_replies, 2);
+        assert_eq!(details.duplicate_replies, 0);
+    }
+
+    #[test]
+    fn corrupted_reply() {
+        let mut replies = UdpReplies::default();
+        let (payload, mut details) = probe(5);
+
+        // a byte of the padding or of the send time changed
+        for index in [63, 20] {
+            let mut reply = payload.clone();
+            reply[index] ^= 0xff;
+            assert_eq!(
+                check_udp_reply(&mut replies, &mut details, &payload, &reply),
+                Some(MPingerResult::UdpPayloadMismatch)
+            );
+        }
+
+        // truncated, not a probe, or a probe which wasn't sent yet
+        let (later, _) = probe(6);
+        let replies_of_others: [&[u8]; 3] = [&payload[..32], b"pong", &later];
+        for reply in replies_of_others {
+            assert_eq!(
+                check_udp_reply(&mut replies, &mut details, &payload, reply),
+                Some(MPingerResult::UdpPayloadMismatch)
+            );
+        }
+
+        // a reflected probe must be the same apart from the stamps
+        let mut reply = payload.clone();
+        assert!(MPingerUdpPacket::reflect(
+            &mut reply,
+            OffsetDateTime::now_utc()
+        ));
+        reply[40] = 1;
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload, &reply),
+            Some(MPingerResult::UdpPayloadMismatch)
+        );
+
+        // none of them was counted
+        assert_eq!(replies.highest, None);
+        assert_eq!(details, probe(5).1);
+    }
+
+    #[test]
+    fn packet_round_trip() {
+        let send_time = OffsetDateTime::now_utc() - time::Duration::milliseconds(30);
+        let packet = MPingerUdpPacket::new(7, send_time);
+        let payload = packet.to_bytes();
+        assert_eq!(payload.len(), UDP_PAYLOAD_SIZE);
+        assert_eq!(MPingerUdpPacket::parse(&payload), Some(packet.clone()));
+
+        // the reflector received it 10 ms after it was sent
+        let mut reply = payload.clone();
+        let receive_time = send_time + time::Duration::milliseconds(10);
+        assert!(MPingerUdpPacket::reflect(&mut reply, receive_time));
+        let reflected = MPingerUdpPacket::parse(&reply).unwrap();
+        assert_eq!(reflected.seq, 7);
+        assert_eq!(reflected.send_time, send_time);
+        assert_eq!(reflected.reflector_receive_time, Some(receive_time));
+        let reflector_send_time = reflected.reflector_send_time.unwrap();
+        assert!(reflector_send_time >= receive_time);
+
+        // a reflected probe isn't reflected again
+        assert!(!MPingerUdpPacket::reflect(&mut reply.clone(), receive_time));
+
+        // and sent back 5 ms after it was received
+        let reflector_send_time = receive_time + time::Duration::milliseconds(5);
+        reply[32..40].copy_from_slice(&to_udp_time(Some(reflector_send_time)));
+
+        let mut replies = UdpReplies::default();
+        let mut details = MPingerUdpDetails {
+            seq: 7,
+            payload_size: payload.len(),
+            ..Default::default()
+        };
+        let before_reply = OffsetDateTime::now_utc();
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload, &reply),
+            Some(MPingerResult::Success)
+        );
+        assert_eq!(
+            details.forward_delay,
+            Some(time::Duration::milliseconds(10))
+        );
+        assert_eq!(details.reflector_time, Some(Duration::from_millis(5)));
+        let reverse_delay = details.reverse_delay.unwrap();
+        assert!(reverse_delay >= before_reply - reflector_send_time);
+        assert!(reverse_delay <= OffsetDateTime::now_utc() - reflector_send_time);
+
+        // an echo has no one-way delays
+        let mut details = MPingerUdpDetails {
+            seq: 8,
+            ..Default::default()
+        };
+        let payload = MPingerUdpPacket::new(8, send_time).to_bytes();
+        assert_eq!(
+            check_udp_reply(&mut replies, &mut details, &payload, &payload),
+            Some(MPingerResult::Success)
+        );
+        assert_eq!(details.forward_delay, None);
+        assert_eq!(details.reverse_delay, None);
+        assert_eq!(details.reflector_time, None);
+    }
+
+    #[test]
+    fn padded_packet() {
+        let packet = MPingerUdpPacket::new(1, OffsetDateTime::now_utc());
+        let mut payload = packet.to_bytes();
+        payload.resize(1000, 0);
+        assert_eq!(MPingerUdpPacket::parse(&payload), Some(packet));
+
+        // the padding is sent back as it is
+        assert!(MPingerUdpPacket::reflect(
+            &mut payload,
+            OffsetDateTime::now_utc()
+        ));
+        assert_eq!(payload.len(), 1000);
+        assert!(payload[UDP_PAYLOAD_SIZE..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let payload = MPingerUdpPacket::new(1, OffsetDateTime::now_utc()).to_bytes();
+
+        let mut unknown_version = payload.clone();
+        unknown_version[4] = UDP_VERSION + 1;
+        let mut wrong_magic = payload.clone();
+        wrong_magic[..4].copy_from_slice(b"PING");
+        // the send time is always set
+        let mut no_send_time = payload.clone();
+        no_send_time[16..24].fill(0);
+
+        let packets: [&[u8]; 6] = [
+            &unknown_version,
+            &wrong_magic,
+            &no_send_time,
+            &payload[..UDP_PAYLOAD_SIZE - 1],
+            &payload[..4],
+            b"",
+        ];
+        for packet in packets {
+            assert_eq!(MPingerUdpPacket::parse(packet), None, "{:?}", packet);
+            assert!(!MPingerUdpPacket::reflect(
+                &mut packet.to_vec(),
+                OffsetDateTime::now_utc()
             ));
         }
     }
@@ -1,34 +1,85 @@
-use std::net::{Ipv4Addr, ToSocketAddrs};
+use crate::mpinger::MPingerIpFamily;
+use std::net::{IpAddr, ToSocketAddrs};
+
+// Split "host", "host:port", "[v6]", "[v6]:port" or a bare IPv6 literal into host and port parts
+fn split_host_port(host_port: &str, default_port: u16) -> Result<(&str, u16), String> {
+    if let Some(rest) = host_port.strip_prefix('[') {
+        let (host, rest) = rest
+            .split_once(']')
+            .ok_or("Missing closing bracket in IPv6 address".to_string())?;
+
+        let port = match rest.strip_prefix(':') {
+            Some(port) => port
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid port: {}", e))?,
+            None if rest.is_empty() => default_port,
+            None => return Err(format!("Invalid address: {}", host_port)),
+        };
+
+        return Ok((host, port));
+    }
 
-pub fn parse_host_port(host_port: &str, default_port: u16) -> Result<(Ipv4Addr, u16), String> {
     let parts: Vec<&str> = host_port.split(':').collect();
 
-    let (host, port) = match parts.len() {
-        1 => (parts[0], Some(default_port)),
-        2 => (
+    match parts.len() {
+        1 => Ok((parts[0], default_port)),
+        2 => Ok((
             parts[0],
-            Some(
-                parts[1]
-                    .parse::<u16>()
-                    .map_err(|e| format!("Invalid port: {}", e))?,
-            ),
-        ),
-        _ => (host_port, Some(default_port)),
-    };
+            parts[1]
+                .parse::<u16>()
+                .map_err(|e| format!("Invalid port: {}", e))?,
+        )),
+        // unbracketed IPv6 literal, can't carry a port
+        _ => Ok((host_port, default_port)),
+    }
+}
 
-    let port = port.unwrap_or(default_port);
+// returns the host part and all the addresses it resolves to
+pub fn parse_host_port(
+    host_port: &str,
+    default_port: u16,
+    ip_family: MPingerIpFamily,
+) -> Result<(String, Vec<IpAddr>, u16), String> {
+    let (host, port) = split_host_port(host_port, default_port)?;
+    let addrs = resolve_host(host, port, ip_family)?;
 
+    Ok((host.to_string(), addrs, port))
+}
+
+// resolve a hostname or parse an IP literal, returns at least one address of the given family
+pub fn resolve_host(
+    host: &str,
+    port: u16,
+    ip_family: MPingerIpFamily,
+) -> Result<Vec<IpAddr>, String> {
     // Resolve hostname to IP address
-    let socket_addr = format!("{}:{}", host, port)
-        .to_socket_addrs()
-        .map_err(|e| format!("Failed to resolve hostname: {}", e))?
-        .next()
-        .ok_or("No address resolved".to_string())?;
+    let mut addrs: Vec<IpAddr> = match host.parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve hostname: {}", e))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
 
-    match socket_addr.ip() {
-        std::net::IpAddr::V4(ipv4) => Ok((ipv4, port)),
-        _ => Err("IPv6 addresses not supported".to_string()),
+    addrs.retain(|ip| ip_family.matches(ip));
+    // keep the resolver order, but drop duplicates
+    let mut seen = Vec::with_capacity(addrs.len());
+    addrs.retain(|ip| {
+        let is_new = !seen.contains(ip);
+        seen.push(*ip);
+        is_new
+    });
+
+    if addrs.is_empty() {
+        return match ip_family {
+            MPingerIpFamily::Any => Err("No address resolved".to_string()),
+            MPingerIpFamily::V4 => Err("No IPv4 address resolved".to_string()),
+            MPingerIpFamily::V6 => Err("No IPv6 address resolved".to_string()),
+        };
     }
+
+    Ok(addrs)
 }
 
 pub struct RunningAverage {
@@ -86,3 +137,343 @@ impl RunningAverage {
         self.count = 0;
     }
 }
+
+// the first 2^HISTOGRAM_LINEAR_BITS values have a bucket each, every power of two above is split
+// into 2^(HISTOGRAM_LINEAR_BITS - 1) buckets, values are recorded within 1/64 of their value
+const HISTOGRAM_LINEAR_BITS: u32 = 7;
+const HISTOGRAM_LINEAR_BUCKETS: usize = 1 << HISTOGRAM_LINEAR_BITS;
+const HISTOGRAM_SUB_BUCKETS: usize = 1 << (HISTOGRAM_LINEAR_BITS - 1);
+
+// Log-linear histogram, e.g. of RTTs in us, for percentiles over all the values
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    // grows up to the bucket of the largest value
+    counts: Vec<u64>,
+    count: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, value: u64) {
+        let index = Histogram::bucket_index(value);
+        if index >= self.counts.len() {
+            self.counts.resize(index + 1, 0);
+        }
+        self.counts[index] += 1;
+
+        self.min = if self.count == 0 {
+            value
+        } else {
+            self.min.min(value)
+        };
+        self.max = self.max.max(value);
+        self.count += 1;
+    }
+
+    // adds the values of another histogram, e.g. of all the destinations
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.count == 0 {
+            return;
+        }
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+
+        self.min = if self.count == 0 {
+            other.min
+        } else {
+            self.min.min(other.min)
+        };
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<u64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    // value below which the given percentage of the values are, e.g. 99.9, interpolated
+    // within its bucket as if the values of the bucket were spread evenly over it
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank =
+            ((percentile.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut total = 0;
+        for (index, &count) in self.counts.iter().enumerate() {
+            if total + count >= rank {
+                // the smallest and largest values are known exactly
+                let lowest = Histogram::bucket_lowest(index).max(self.min);
+                let highest = Histogram::bucket_highest(index).min(self.max);
+                let offset = (highest - lowest) as f64 * (rank - total) as f64 / count as f64;
+                return Some(lowest + offset as u64);
+            }
+            total += count;
+        }
+
+        Some(self.max)
+    }
+
+    fn bucket_index(value: u64) -> usize {
+        if value < HISTOGRAM_LINEAR_BUCKETS as u64 {
+            return value as usize;
+        }
+
+        // powers of two above the linear buckets, then the top bits below the highest one
+        let msb = 63 - value.leading_zeros();
+        let octave = (msb - HISTOGRAM_LINEAR_BITS) as usize;
+        let sub_bucket =
+            (value >> (msb - (HISTOGRAM_LINEAR_BITS - 1))) as usize & (HISTOGRAM_SUB_BUCKETS - 1);
+
+        HISTOGRAM_LINEAR_BUCKETS + octave * HISTOGRAM_SUB_BUCKETS + sub_bucket
+    }
+
+    fn bucket_lowest(index: usize) -> u64 {
+        if index < HISTOGRAM_LINEAR_BUCKETS {
+            return index as u64;
+        }
+
+        let octave = (index - HISTOGRAM_LINEAR_BUCKETS) / HISTOGRAM_SUB_BUCKETS;
+        let sub_bucket = ((index - HISTOGRAM_LINEAR_BUCKETS) % HISTOGRAM_SUB_BUCKETS) as u64;
+        let msb = octave as u32 + HISTOGRAM_LINEAR_BITS;
+
+        (1u64 << msb) | (sub_bucket << (msb - (HISTOGRAM_LINEAR_BITS - 1)))
+    }
+
+    fn bucket_highest(index: usize) -> u64 {
+        if index < HISTOGRAM_LINEAR_BUCKETS {
+            return index as u64;
+        }
+
+        let octave = (index - HISTOGRAM_LINEAR_BUCKETS) / HISTOGRAM_SUB_BUCKETS;
+        let shift = octave as u32 + 1;
+
+        Histogram::bucket_lowest(index) + ((1u64 << shift) - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_empty() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.min(), None);
+        assert_eq!(histogram.max(), None);
+        assert_eq!(histogram.percentile(50.0), None);
+
+        // merging an empty histogram changes nothing
+        let mut merged = Histogram::new();
+        merged.add(10);
+        merged.merge(&histogram);
+        assert_eq!(merged.count(), 1);
+        assert_eq!(merged.min(), Some(10));
+    }
+
+    #[test]
+    fn histogram_bucket_boundaries() {
+        // a bucket for each of the first values
+        for value in 0..HISTOGRAM_LINEAR_BUCKETS as u64 {
+            assert_eq!(Histogram::bucket_index(value), value as usize);
+        }
+
+        // then buckets of 2, 4, 8... values, the first one right after the linear buckets
+        assert_eq!(Histogram::bucket_index(127), 127);
+        assert_eq!(Histogram::bucket_index(128), 128);
+        assert_eq!(Histogram::bucket_index(129), 128);
+        assert_eq!(Histogram::bucket_index(130), 129);
+        assert_eq!(Histogram::bucket_index(255), 191);
+        assert_eq!(Histogram::bucket_index(256), 192);
+        assert_eq!(Histogram::bucket_index(259), 192);
+        assert_eq!(Histogram::bucket_index(260), 193);
+
+        // consecutive buckets without gaps, none wider than 1/64 of its values
+        let last = Histogram::bucket_index(u64::MAX);
+        for index in 0..last {
+            let lowest = Histogram::bucket_lowest(index);
+            let highest = Histogram::bucket_highest(index);
+            assert_eq!(Histogram::bucket_index(lowest), index);
+            assert_eq!(Histogram::bucket_index(highest), index);
+            assert_eq!(Histogram::bucket_lowest(index + 1), highest + 1);
+            assert!(highest - lowest <= lowest / 64);
+        }
+        assert_eq!(Histogram::bucket_highest(last), u64::MAX);
+    }
+
+    #[test]
+    fn histogram_percentiles() {
+        let mut histogram = Histogram::new();
+        for value in 1..=100 {
+            histogram.add(value);
+        }
+
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.min(), Some(1));
+        assert_eq!(histogram.max(), Some(100));
+        assert_eq!(histogram.percentile(0.0), Some(1));
+        assert_eq!(histogram.percentile(50.0), Some(50));
+        assert_eq!(histogram.percentile(90.0), Some(90));
+        assert_eq!(histogram.percentile(99.9), Some(100));
+        assert_eq!(histogram.percentile(100.0), Some(100));
+        // out of range percentiles are clamped
+        assert_eq!(histogram.percentile(150.0), Some(100));
+    }
+
+    #[test]
+    fn histogram_percentile_interpolation() {
+        // 1024..=1039 all fall in a single bucket 16 values wide
+        let mut histogram = Histogram::new();
+        for value in (1024..1040).cycle().take(160) {
+            histogram.add(value);
+        }
+        assert_eq!(Histogram::bucket_index(1024), Histogram::bucket_index(1039));
+
+        assert_eq!(histogram.percentile(25.0), Some(1027));
+        assert_eq!(histogram.percentile(50.0), Some(1031));
+        assert_eq!(histogram.percentile(100.0), Some(1039));
+
+        // never outside of the values added
+        let mut histogram = Histogram::new();
+        histogram.add(1030);
+        histogram.add(1031);
+        assert_eq!(histogram.percentile(1.0), Some(1030));
+        assert_eq!(histogram.percentile(100.0), Some(1031));
+    }
+
+    #[test]
+    fn histogram_merge() {
+        let mut low = Histogram::new();
+        let mut high = Histogram::new();
+        let mut all = Histogram::new();
+        for value in 0..1000 {
+            match value % 2 {
+                0 => low.add(value),
+                _ => high.add(value * 1000),
+            }
+            all.add(if value % 2 == 0 { value } else { value * 1000 });
+        }
+
+        low.merge(&high);
+        assert_eq!(low, all);
+    }
+
+    #[test]
+    fn running_average() {
+        let mut average = RunningAverage::new(3);
+        assert_eq!(average.get(), None);
+
+        average.add(1);
+        average.add(2);
+        assert_eq!(average.get(), Some(1.5));
+
+        // the oldest value is replaced once full
+        average.add(3);
+        average.add(7);
+        assert_eq!(average.count(), 3);
+        assert_eq!(average.get(), Some(4.0));
+
+        average.clear();
+        assert_eq!(average.get(), None);
+    }
+
+    #[test]
+    fn split_host_and_port() {
+        assert_eq!(
+            split_host_port("example.com:8080", 80),
+            Ok(("example.com", 8080))
+        );
+        assert_eq!(split_host_port("192.0.2.1:53", 80), Ok(("192.0.2.1", 53)));
+        // no port
+        assert_eq!(split_host_port("example.com", 80), Ok(("example.com", 80)));
+        assert_eq!(split_host_port("192.0.2.1", 443), Ok(("192.0.2.1", 443)));
+
+        // IPv6 addresses with a port are bracketed
+        assert_eq!(split_host_port("[::1]:443", 80), Ok(("::1", 443)));
+        assert_eq!(
+            split_host_port("[2001:db8::1]", 80),
+            Ok(("2001:db8::1", 80))
+        );
+        assert_eq!(split_host_port("::1", 80), Ok(("::1", 80)));
+        assert_eq!(split_host_port("2001:db8::1", 80), Ok(("2001:db8::1", 80)));
+    }
+
+    #[test]
+    fn split_host_and_port_errors() {
+        let e = split_host_port("[::1:443", 80).unwrap_err();
+        assert!(e.contains("Missing closing bracket"), "{}", e);
+        let e = split_host_port("[::1]443", 80).unwrap_err();
+        assert!(e.contains("Invalid address"), "{}", e);
+        for host_port in ["[::1]:http", "[::1]:", "example.com:70000", "example.com:"] {
+            let e = split_host_port(host_port, 80).unwrap_err();
+            assert!(e.contains("Invalid port"), "{}: {}", host_port, e);
+        }
+    }
+
+    #[test]
+    fn resolve_ip_family() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(
+            resolve_host("192.0.2.1", 80, MPingerIpFamily::Any),
+            Ok(vec![v4])
+        );
+        assert_eq!(
+            resolve_host("192.0.2.1", 80, MPingerIpFamily::V4),
+            Ok(vec![v4])
+        );
+        assert_eq!(
+            resolve_host("192.0.2.1", 80, MPingerIpFamily::V6),
+            Err("No IPv6 address resolved".to_string())
+        );
+        assert_eq!(
+            resolve_host("2001:db8::1", 80, MPingerIpFamily::V6),
+            Ok(vec![v6])
+        );
+        assert_eq!(
+            resolve_host("2001:db8::1", 80, MPingerIpFamily::V4),
+            Err("No IPv4 address resolved".to_string())
+        );
+
+        // from the hosts file
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            resolve_host("localhost", 80, MPingerIpFamily::V4),
+            Ok(vec![localhost])
+        );
+        assert!(resolve_host("localhost", 80, MPingerIpFamily::Any)
+            .unwrap()
+            .contains(&localhost));
+    }
+
+    #[test]
+    fn parse_bracketed_address() {
+        let (host, addrs, port) = parse_host_port("[::1]:443", 80, MPingerIpFamily::Any).unwrap();
+        assert_eq!(host, "::1");
+        assert_eq!(addrs, vec!["::1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(port, 443);
+
+        assert!(parse_host_port("[::1]:443", 80, MPingerIpFamily::V4).is_err());
+        assert!(parse_host_port("[::1:443", 80, MPingerIpFamily::Any).is_err());
+    }
+}
@@ -1,10 +1,12 @@
 use anyhow::Result;
 use clap::Parser;
 use log::{error, info};
+use mpinger::MPingerUdpPacket;
 use std::sync::Arc;
+use time::OffsetDateTime;
 use tokio::net::UdpSocket;
 
-/// UDP Ping-Pong Server
+/// UDP Ping-Pong Server, reflecting MPinger UDP probes with its receive and send times
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -55,32 +57,89 @@ async fn main() -> Result<()> {
             }
         };
 
-        let received_data = &buf[..len];
-
-        // Check if the received message is "ping"
-        if let Ok(message) = std::str::from_utf8(received_data) {
-            info!("Received '{}' from {}", message, addr);
-
-            if message.trim().eq_ignore_ascii_case("ping") {
-                // Send back "pong"
-                let response = b"pong";
-                match socket.send_to(response, addr).await {
-                    Ok(_) => info!("Sent 'pong' to {}", addr),
-                    Err(e) => error!("Failed to send pong to {}: {}", addr, e),
-                }
-            } else {
-                // Echo back whatever was received
-                match socket.send_to(received_data, addr).await {
-                    Ok(_) => info!("Echoed {} bytes to {}", len, addr),
-                    Err(e) => error!("Failed to echo to {}: {}", addr, e),
-                }
+        let receive_time = OffsetDateTime::now_utc();
+
+        let (response, sent) = match respond(&mut buf[..len], receive_time) {
+            Response::Reflected(probe) => (probe, format!("Reflected probe of {} bytes", len)),
+            Response::Pong => {
+                info!("Received 'ping' from {}", addr);
+                (&b"pong"[..], "Sent 'pong'".to_string())
             }
-        } else {
-            // If not valid UTF-8, echo back the raw bytes
-            match socket.send_to(received_data, addr).await {
-                Ok(_) => info!("Echoed {} bytes (binary) to {}", len, addr),
-                Err(e) => error!("Failed to echo to {}: {}", addr, e),
+            Response::Echo(data) => (data, format!("Echoed {} bytes", len)),
+        };
+        match socket.send_to(response, addr).await {
+            Ok(_) => info!("{} to {}", sent, addr),
+            Err(e) => error!("Failed to send {} bytes to {}: {}", response.len(), addr, e),
+        }
+    }
+}
+
+enum Response<'a> {
+    // the probe stamped with the receive and send times
+    Reflected(&'a [u8]),
+    Pong,
+    Echo(&'a [u8]),
+}
+
+// MPinger probes are stamped and sent back, for one-way delays on the client,
+// "ping" gets a "pong" and anything else is echoed back
+fn respond(data: &mut [u8], receive_time: OffsetDateTime) -> Response<'_> {
+    if MPingerUdpPacket::reflect(data, receive_time) {
+        return Response::Reflected(data);
+    }
+
+    match std::str::from_utf8(data) {
+        Ok(message) if message.trim().eq_ignore_ascii_case("ping") => Response::Pong,
+        _ => Response::Echo(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_is_stamped() {
+        let send_time = OffsetDateTime::now_utc();
+        let mut data = MPingerUdpPacket::new(3, send_time).to_bytes();
+        let receive_time = OffsetDateTime::now_utc();
+
+        let reply = match respond(&mut data, receive_time) {
+            Response::Reflected(reply) => reply.to_vec(),
+            _ => panic!("probe not reflected"),
+        };
+        let packet = MPingerUdpPacket::parse(&reply).unwrap();
+        assert_eq!(packet.seq, 3);
+        assert_eq!(packet.send_time, send_time);
+        assert_eq!(packet.reflector_receive_time, Some(receive_time));
+        assert!(packet.reflector_send_time.unwrap() >= receive_time);
+    }
+
+    #[test]
+    fn other_messages() {
+        assert!(matches!(
+            respond(&mut b"PING\n".to_vec(), OffsetDateTime::now_utc()),
+            Response::Pong
+        ));
+
+        let messages: [&[u8]; 2] = [b"hello", &[0xff, 0x00, 0x80]];
+        for message in messages {
+            match respond(&mut message.to_vec(), OffsetDateTime::now_utc()) {
+                Response::Echo(data) => assert_eq!(data, message),
+                _ => panic!("{:?} not echoed", message),
             }
         }
+
+        // a probe which was already reflected is echoed as it is
+        let mut data = MPingerUdpPacket::new(1, OffsetDateTime::now_utc()).to_bytes();
+        assert!(MPingerUdpPacket::reflect(
+            &mut data,
+            OffsetDateTime::now_utc()
+        ));
+        let reflected = data.clone();
+        match respond(&mut data, OffsetDateTime::now_utc()) {
+            Response::Echo(echoed) => assert_eq!(echoed, reflected),
+            _ => panic!("reflected probe not echoed"),
+        }
     }
 }